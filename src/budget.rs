@@ -0,0 +1,55 @@
+use std::{fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Limits after which the scheduler stops requesting and drains.
+/// Each applies to one run, not counting runs resumed from.
+#[derive(Clone, Debug, Default)]
+pub struct Budget {
+    /// Total number of pages and assets requested.
+    pub max_requests: Option<usize>,
+    /// Total size of the bodies downloaded.
+    pub max_bytes: Option<u64>,
+    /// Number of pages and assets requested from each host.
+    /// URLs of a host over it are skipped rather than ending the crawl.
+    pub max_pages_per_host: Option<usize>,
+    /// Time since the crawl started.
+    pub max_time: Option<Duration>,
+}
+
+impl Budget {
+    /// The budget used up, if any.
+    pub fn exceeded(&self, requests: usize, bytes: u64, elapsed: Duration) -> Option<StopReason> {
+        if self.max_requests.is_some_and(|max| requests >= max) {
+            Some(StopReason::Requests)
+        } else if self.max_bytes.is_some_and(|max| bytes >= max) {
+            Some(StopReason::Bytes)
+        } else if self.max_time.is_some_and(|max| elapsed >= max) {
+            Some(StopReason::Time)
+        } else {
+            None
+        }
+    }
+}
+
+/// Why the crawl stopped before running out of URLs.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StopReason {
+    /// SIGINT or SIGTERM.
+    Signal,
+    Requests,
+    Bytes,
+    Time,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Signal => "signal",
+            Self::Requests => "request budget",
+            Self::Bytes => "byte budget",
+            Self::Time => "time budget",
+        })
+    }
+}
@@ -0,0 +1,85 @@
+use clap::ValueEnum;
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TrailingSlash {
+    #[default]
+    Keep,
+    /// Add a trailing slash to paths whose last segment has no extension.
+    Add,
+    /// Remove the trailing slash from paths other than `/`.
+    Remove,
+}
+
+/// How URLs are normalized before they are deduplicated,
+/// beyond the lowercase host and the default port dropped by [`Url::parse`].
+#[derive(Debug)]
+pub struct Canonicalizer {
+    /// Query parameters whose names match any of these are removed.
+    pub strip_params: Vec<Regex>,
+    pub sort_query: bool,
+    pub trailing_slash: TrailingSlash,
+    /// Treat the `<link rel="canonical">` of a page as its URL.
+    pub prefer_canonical: bool,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Self {
+        Self {
+            strip_params: Vec::new(),
+            sort_query: false,
+            trailing_slash: TrailingSlash::Keep,
+            prefer_canonical: false,
+        }
+    }
+}
+
+impl Canonicalizer {
+    pub fn canonicalize(&self, mut url: Url) -> Url {
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Add => {
+                let path = url.path();
+                let last = path.rsplit('/').next().unwrap_or_default();
+                if !path.ends_with('/') && !last.contains('.') {
+                    let path = format!("{path}/");
+                    url.set_path(&path);
+                }
+            }
+            TrailingSlash::Remove => {
+                let path = url.path();
+                if path.len() > 1 && path.ends_with('/') {
+                    let path = path.trim_end_matches('/').to_owned();
+                    url.set_path(&path);
+                }
+            }
+        }
+        if url.query().is_some() && (self.sort_query || !self.strip_params.is_empty()) {
+            self.normalize_query(&mut url);
+        }
+        url
+    }
+
+    fn normalize_query(&self, url: &mut Url) {
+        let pairs: Vec<_> = url.query_pairs().into_owned().collect();
+        let mut kept: Vec<_> = pairs
+            .iter()
+            .filter(|(name, _)| !self.strip_params.iter().any(|p| p.is_match(name)))
+            .cloned()
+            .collect();
+        if self.sort_query {
+            kept.sort();
+        }
+        if kept == pairs {
+            return;
+        }
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+use regex::Regex;
+use std::sync::OnceLock;
+
+const UTF_8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Bytes of an HTML document searched for `<meta charset>`, per the HTML spec.
+const PRESCAN_LEN: usize = 1024;
+
+/// The encoding of an HTML document, sniffed like the HTML spec does:
+/// from a byte order mark, the `charset` of `content_type`,
+/// a `<meta>` in the first 1024 bytes, in this order.
+///
+/// Failing those, UTF-8 if `body` is valid UTF-8, or else windows-1252.
+pub fn sniff_encoding(body: &[u8], content_type: Option<&str>) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(body) {
+        return encoding;
+    }
+    if let Some(encoding) = content_type
+        .and_then(charset_parameter)
+        .and_then(|charset| Encoding::for_label(charset.as_bytes()))
+    {
+        return encoding;
+    }
+    if let Some(encoding) = prescan(&body[..body.len().min(PRESCAN_LEN)]) {
+        return encoding;
+    }
+    match std::str::from_utf8(body) {
+        Ok(_) => UTF_8,
+        Err(_) => WINDOWS_1252,
+    }
+}
+
+/// Decode `body` with the encoding sniffed, without a byte order mark.
+pub fn decode_html(body: &[u8], content_type: Option<&str>) -> (String, &'static Encoding) {
+    let encoding = sniff_encoding(body, content_type);
+    let (text, encoding, _) = encoding.decode(body);
+    (text.into_owned(), encoding)
+}
+
+/// Encode `text` as `encoding`, after a UTF-8 byte order mark if `bom`.
+///
+/// Encodings that cannot be encoded to, such as UTF-16, are replaced by UTF-8
+/// with a byte order mark, which browsers obey over any `<meta charset>`.
+pub fn encode_html(text: &str, encoding: &'static Encoding, bom: bool) -> Vec<u8> {
+    let (bytes, used, _) = encoding.encode(text);
+    let mut encoded = Vec::with_capacity(bytes.len() + UTF_8_BOM.len());
+    if used == UTF_8 && (bom || used != encoding) {
+        encoded.extend_from_slice(UTF_8_BOM);
+    }
+    encoded.extend_from_slice(&bytes);
+    encoded
+}
+
+/// The `charset` parameter of a `Content-Type`.
+fn charset_parameter(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']))
+    })
+}
+
+/// The encoding declared by the first `<meta charset>`
+/// or `<meta http-equiv="content-type" content="…; charset=…">`
+/// outside comments.
+fn prescan(head: &[u8]) -> Option<&'static Encoding> {
+    static COMMENT: OnceLock<Regex> = OnceLock::new();
+    static META: OnceLock<Regex> = OnceLock::new();
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let comment = COMMENT.get_or_init(|| Regex::new(r"(?s)<!--.*?(-->|$)").unwrap());
+    let meta = META.get_or_init(|| Regex::new(r"(?i)<meta[\s/][^>]*").unwrap());
+    let attribute = ATTRIBUTE
+        .get_or_init(|| Regex::new(r#"([^\s/=>]+)\s*(?:=\s*("[^"]*"|'[^']*'|[^\s>]*))?"#).unwrap());
+    let head = String::from_utf8_lossy(head);
+    let head = comment.replace_all(&head, "");
+    for meta in meta.find_iter(&head) {
+        let mut charset = None;
+        let mut content = None;
+        let mut pragma = false;
+        for attribute in attribute.captures_iter(&meta.as_str()[5..]) {
+            let name = attribute[1].to_ascii_lowercase();
+            let value = attribute
+                .get(2)
+                .map(|value| value.as_str().trim_matches(['"', '\'']))
+                .unwrap_or_default();
+            match name.as_str() {
+                "charset" if charset.is_none() => charset = Some(value.to_owned()),
+                "content" if content.is_none() => content = Some(value.to_owned()),
+                "http-equiv" => pragma |= value.eq_ignore_ascii_case("content-type"),
+                _ => {}
+            }
+        }
+        let label = charset.or_else(|| {
+            let content = content.filter(|_| pragma)?.to_ascii_lowercase();
+            let (_, charset) = content.split_once("charset")?;
+            let charset = charset.trim_start().strip_prefix('=')?;
+            let charset = charset.trim_start().trim_start_matches(['"', '\'']);
+            let end = charset
+                .find(|c: char| c == ';' || c == '"' || c == '\'' || c.is_whitespace())
+                .unwrap_or(charset.len());
+            Some(charset[..end].to_owned())
+        });
+        if let Some(encoding) = label.and_then(|label| Encoding::for_label(label.trim().as_bytes()))
+        {
+            // A document read as ASCII cannot be in UTF-16.
+            return Some(match encoding {
+                encoding if encoding == UTF_16BE || encoding == UTF_16LE => UTF_8,
+                encoding if encoding == X_USER_DEFINED => WINDOWS_1252,
+                encoding => encoding,
+            });
+        }
+    }
+    None
+}
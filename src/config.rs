@@ -1,43 +1,181 @@
-use crate::ring::Ring;
+use crate::{
+    budget::Budget,
+    canonical::Canonicalizer,
+    file::LinkKind,
+    middle::DEFAULT_MAX_HTML_SIZE,
+    mime::MimePolicy,
+    retry::RetryPolicy,
+    ring::Ring,
+    robots::DEFAULT_USER_AGENT,
+    rules::{Rule, RuleOrder},
+    schedule::DEFAULT_TIMEOUT,
+    warc::WarcConfig,
+};
+use clap::ValueEnum;
 use regex::Regex;
-use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
 
 pub const DEFAULT_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_GLOBAL_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_FOLLOW: [LinkKind; 4] = [
+    LinkKind::Anchor,
+    LinkKind::Area,
+    LinkKind::Frame,
+    LinkKind::Refresh,
+];
+pub const DEFAULT_ASSETS: [LinkKind; 1] = [LinkKind::Image];
+
+/// How pages and assets are stored.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    /// Files named by URL id in the HTML and non-HTML directories.
+    #[default]
+    Directories,
+    /// Files named after the URLs, in directories named after the hosts and paths.
+    Mirror,
+    /// WARC/1.1 files in the WARC directory.
+    Warc,
+}
 
 #[derive(Debug)]
 pub struct SchedulerConfig {
+    /// Connection timeout for each request, and a multiple of it for the whole request.
+    pub connection_timeout: Duration,
+    /// Delay between each request to the same host.
     pub delay: Duration,
+    /// Delay between each request overall.
+    pub global_delay: Duration,
+    /// Delays for the hosts matching the patterns,
+    /// taking precedence over robots.txt `Crawl-delay` and `delay`.
+    pub host_delays: Vec<(Regex, Duration)>,
     pub filter: Regex,
     pub blacklist: Regex,
+    /// Include and exclude rules, taking precedence over `filter` and `blacklist`.
+    pub rules: Vec<Rule>,
+    pub rule_order: RuleOrder,
+    /// Applied to every URL before it is assigned an id.
+    pub canonicalizer: Canonicalizer,
+    /// Kinds of links that are followed through `filter` and `blacklist`.
+    pub follow: BTreeSet<LinkKind>,
+    /// Kinds of links that are requested and saved as assets
+    /// if not followed.
+    pub assets: BTreeSet<LinkKind>,
+    /// Assets not matching this are recorded but not requested.
+    pub asset_filter: Regex,
+    /// Assets matching this are recorded but not requested.
+    pub asset_blacklist: Regex,
+    /// Only request assets on the same host as the page they are found on.
+    pub same_host_assets: bool,
     pub disregard_html: bool,
     pub disregard_other: bool,
+    /// Save HTML as the bytes received rather than decoded to UTF-8.
+    pub raw_html: bool,
+    /// Which content types are parsed, saved, or not downloaded,
+    /// with the patterns in lowercase, as set by [`Self::mime`].
+    pub mime: Arc<MimePolicy>,
+    /// Largest HTML body read into memory to parse.
+    pub max_html_size: Option<u64>,
+    /// Largest non-HTML body downloaded.
+    pub max_body_size: Option<u64>,
     pub html_dir: String,
     pub other_dir: String,
     pub log_dir: String,
+    pub output: Output,
+    /// Used if `output` is `Mirror`.
+    pub mirror_dir: String,
+    /// Used if `output` is `Warc`.
+    pub warc: WarcConfig,
+    /// After the crawl, point the links in saved HTML at the saved files.
+    pub rewrite_links: bool,
     pub ring: Option<Ring>,
+    /// Links followed from pages at this depth are recorded but not requested.
+    pub max_depth: Option<usize>,
+    pub budget: Budget,
+    pub retry: RetryPolicy,
+    /// Sent as the User-Agent and matched against robots.txt groups.
+    pub user_agent: String,
+    pub ignore_robots: bool,
+    /// Discover sitemaps of each origin and add the URLs they list.
+    pub sitemaps: bool,
+    /// Obey `rel="nofollow"`, `<meta name="robots">` and `X-Robots-Tag`.
+    pub page_robots: bool,
+    /// Save pages and assets even if their content is already saved.
+    pub keep_duplicates: bool,
+    /// Treat HTML pages whose SimHash differs in at most this many bits
+    /// as duplicates.
+    pub near_duplicate: Option<u32>,
 }
 
 impl Default for SchedulerConfig {
     fn default() -> Self {
         Self {
+            connection_timeout: DEFAULT_TIMEOUT,
             delay: DEFAULT_DELAY,
+            global_delay: DEFAULT_GLOBAL_DELAY,
+            host_delays: Vec::new(),
             filter: Regex::new(".*").unwrap(),
             blacklist: Regex::new("#").unwrap(),
+            rules: Vec::new(),
+            rule_order: RuleOrder::FirstMatch,
+            canonicalizer: Canonicalizer::default(),
+            follow: BTreeSet::from(DEFAULT_FOLLOW),
+            assets: BTreeSet::from(DEFAULT_ASSETS),
+            asset_filter: Regex::new(".*").unwrap(),
+            asset_blacklist: Regex::new("#").unwrap(),
+            same_host_assets: false,
             disregard_html: false,
             disregard_other: false,
+            raw_html: false,
+            mime: Arc::new(MimePolicy::default()),
+            max_html_size: Some(DEFAULT_MAX_HTML_SIZE),
+            max_body_size: None,
             html_dir: "html".to_owned(),
             other_dir: "other".to_owned(),
             log_dir: "log".to_owned(),
+            output: Output::Directories,
+            mirror_dir: "mirror".to_owned(),
+            warc: WarcConfig::default(),
+            rewrite_links: false,
             ring: None,
+            max_depth: None,
+            budget: Budget::default(),
+            retry: RetryPolicy::default(),
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            ignore_robots: false,
+            sitemaps: false,
+            page_robots: false,
+            keep_duplicates: false,
+            near_duplicate: None,
         }
     }
 }
 
 impl SchedulerConfig {
+    pub fn connection_timeout(self, connection_timeout: Duration) -> Self {
+        Self {
+            connection_timeout,
+            ..self
+        }
+    }
+
     pub fn delay(self, delay: Duration) -> Self {
         Self { delay, ..self }
     }
 
+    pub fn global_delay(self, global_delay: Duration) -> Self {
+        Self {
+            global_delay,
+            ..self
+        }
+    }
+
+    pub fn host_delay(mut self, host: Regex, delay: Duration) -> Self {
+        self.host_delays.push((host, delay));
+        self
+    }
+
     pub fn filter(self, filter: Regex) -> Self {
         Self { filter, ..self }
     }
@@ -46,6 +184,51 @@ impl SchedulerConfig {
         Self { blacklist, ..self }
     }
 
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rule_order(self, rule_order: RuleOrder) -> Self {
+        Self { rule_order, ..self }
+    }
+
+    pub fn canonicalizer(self, canonicalizer: Canonicalizer) -> Self {
+        Self {
+            canonicalizer,
+            ..self
+        }
+    }
+
+    pub fn follow(self, follow: BTreeSet<LinkKind>) -> Self {
+        Self { follow, ..self }
+    }
+
+    pub fn assets(self, assets: BTreeSet<LinkKind>) -> Self {
+        Self { assets, ..self }
+    }
+
+    pub fn asset_filter(self, asset_filter: Regex) -> Self {
+        Self {
+            asset_filter,
+            ..self
+        }
+    }
+
+    pub fn asset_blacklist(self, asset_blacklist: Regex) -> Self {
+        Self {
+            asset_blacklist,
+            ..self
+        }
+    }
+
+    pub fn same_host_assets(self) -> Self {
+        Self {
+            same_host_assets: true,
+            ..self
+        }
+    }
+
     pub fn disregard_html(self) -> Self {
         Self {
             disregard_html: true,
@@ -60,6 +243,36 @@ impl SchedulerConfig {
         }
     }
 
+    pub fn raw_html(self) -> Self {
+        Self {
+            raw_html: true,
+            ..self
+        }
+    }
+
+    pub fn mime(self, mime: MimePolicy) -> Self {
+        Self {
+            mime: Arc::new(mime.lowercase()),
+            ..self
+        }
+    }
+
+    /// `0` for no limit.
+    pub fn max_html_size(self, max_html_size: u64) -> Self {
+        Self {
+            max_html_size: (max_html_size != 0).then_some(max_html_size),
+            ..self
+        }
+    }
+
+    /// `0` for no limit.
+    pub fn max_body_size(self, max_body_size: u64) -> Self {
+        Self {
+            max_body_size: (max_body_size != 0).then_some(max_body_size),
+            ..self
+        }
+    }
+
     pub fn html_dir(self, html_dir: String) -> Self {
         Self { html_dir, ..self }
     }
@@ -72,10 +285,83 @@ impl SchedulerConfig {
         Self { log_dir, ..self }
     }
 
+    pub fn output(self, output: Output) -> Self {
+        Self { output, ..self }
+    }
+
+    pub fn mirror_dir(self, mirror_dir: String) -> Self {
+        Self { mirror_dir, ..self }
+    }
+
+    pub fn warc(self, warc: WarcConfig) -> Self {
+        Self { warc, ..self }
+    }
+
+    pub fn rewrite_links(self) -> Self {
+        Self {
+            rewrite_links: true,
+            ..self
+        }
+    }
+
     pub fn with_number_of_rings(self, number_of_rings: u8) -> Self {
         Self {
             ring: Some(Ring::new(number_of_rings)),
             ..self
         }
     }
+
+    pub fn max_depth(self, max_depth: usize) -> Self {
+        Self {
+            max_depth: Some(max_depth),
+            ..self
+        }
+    }
+
+    pub fn budget(self, budget: Budget) -> Self {
+        Self { budget, ..self }
+    }
+
+    pub fn retry(self, retry: RetryPolicy) -> Self {
+        Self { retry, ..self }
+    }
+
+    pub fn user_agent(self, user_agent: String) -> Self {
+        Self { user_agent, ..self }
+    }
+
+    pub fn ignore_robots(self) -> Self {
+        Self {
+            ignore_robots: true,
+            ..self
+        }
+    }
+
+    pub fn sitemaps(self) -> Self {
+        Self {
+            sitemaps: true,
+            ..self
+        }
+    }
+
+    pub fn page_robots(self) -> Self {
+        Self {
+            page_robots: true,
+            ..self
+        }
+    }
+
+    pub fn keep_duplicates(self) -> Self {
+        Self {
+            keep_duplicates: true,
+            ..self
+        }
+    }
+
+    pub fn near_duplicate(self, max_distance: u32) -> Self {
+        Self {
+            near_duplicate: Some(max_distance),
+            ..self
+        }
+    }
 }
@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    budget::Budget,
+    canonical::{Canonicalizer, TrailingSlash},
+    config::{Output, SchedulerConfig},
+    file::LinkKind,
+    mime::MimePolicy,
+    retry::RetryPolicy,
+    rules::RuleOrder,
+    warc::WarcConfig,
+};
+
+/// [`SchedulerConfig`] as written in a TOML file, plus the start URLs.
+///
+/// Delays and timeouts are in milliseconds, except `budget.max_time`
+/// in seconds, like the command line flags.
+/// Fields left out keep their defaults.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_urls: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub global_delay: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blacklist: Option<String>,
+    /// As `ACTION:KIND:PATTERN`, see [`crate::rules::Rule`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rules: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_order: Option<RuleOrder>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow: Option<Vec<LinkKind>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assets: Option<Vec<LinkKind>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_blacklist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_host_assets: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disregard_html: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disregard_other: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_html: Option<bool>,
+    /// `0` for no limit, which TOML cannot write as `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_html_size: Option<u64>,
+    /// `0` for no limit, like `max_html_size`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub html_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub other_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mirror_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rewrite_links: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number_of_rings: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_depth: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore_robots: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sitemaps: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_robots: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_duplicates: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub near_duplicate: Option<u32>,
+    /// Tried in order, the first matching host wins.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_delays: Option<Vec<HostDelay>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub canonicalizer: Option<CanonicalizerSection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<MimePolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warc: Option<WarcConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<BudgetSection>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetrySection>,
+}
+
+impl ConfigFile {
+    /// Set the fields given in the file on `cfg`.
+    pub fn apply(self, mut cfg: SchedulerConfig) -> Result<SchedulerConfig> {
+        let millis = Duration::from_millis;
+        if let Some(timeout) = self.connection_timeout {
+            cfg = cfg.connection_timeout(millis(timeout));
+        }
+        if let Some(delay) = self.delay {
+            cfg = cfg.delay(millis(delay));
+        }
+        if let Some(global_delay) = self.global_delay {
+            cfg = cfg.global_delay(millis(global_delay));
+        }
+        if let Some(filter) = self.filter {
+            cfg = cfg.filter(Regex::new(&filter)?);
+        }
+        if let Some(blacklist) = self.blacklist {
+            cfg = cfg.blacklist(Regex::new(&blacklist)?);
+        }
+        if let Some(rules) = self.rules {
+            cfg.rules = rules
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<Result<_>>()?;
+        }
+        if let Some(rule_order) = self.rule_order {
+            cfg = cfg.rule_order(rule_order);
+        }
+        if let Some(follow) = self.follow {
+            cfg = cfg.follow(follow.into_iter().collect());
+        }
+        if let Some(assets) = self.assets {
+            cfg = cfg.assets(assets.into_iter().collect());
+        }
+        if let Some(asset_filter) = self.asset_filter {
+            cfg = cfg.asset_filter(Regex::new(&asset_filter)?);
+        }
+        if let Some(asset_blacklist) = self.asset_blacklist {
+            cfg = cfg.asset_blacklist(Regex::new(&asset_blacklist)?);
+        }
+        cfg.same_host_assets = self.same_host_assets.unwrap_or(cfg.same_host_assets);
+        cfg.disregard_html = self.disregard_html.unwrap_or(cfg.disregard_html);
+        cfg.disregard_other = self.disregard_other.unwrap_or(cfg.disregard_other);
+        cfg.raw_html = self.raw_html.unwrap_or(cfg.raw_html);
+        if let Some(max_html_size) = self.max_html_size {
+            cfg = cfg.max_html_size(max_html_size);
+        }
+        if let Some(max_body_size) = self.max_body_size {
+            cfg = cfg.max_body_size(max_body_size);
+        }
+        if let Some(html_dir) = self.html_dir {
+            cfg = cfg.html_dir(html_dir);
+        }
+        if let Some(other_dir) = self.other_dir {
+            cfg = cfg.other_dir(other_dir);
+        }
+        if let Some(log_dir) = self.log_dir {
+            cfg = cfg.log_dir(log_dir);
+        }
+        if let Some(output) = self.output {
+            cfg = cfg.output(output);
+        }
+        if let Some(mirror_dir) = self.mirror_dir {
+            cfg = cfg.mirror_dir(mirror_dir);
+        }
+        cfg.rewrite_links = self.rewrite_links.unwrap_or(cfg.rewrite_links);
+        if let Some(number_of_rings) = self.number_of_rings {
+            cfg = cfg.with_number_of_rings(number_of_rings);
+        }
+        cfg.max_depth = self.max_depth.or(cfg.max_depth);
+        if let Some(user_agent) = self.user_agent {
+            cfg = cfg.user_agent(user_agent);
+        }
+        cfg.ignore_robots = self.ignore_robots.unwrap_or(cfg.ignore_robots);
+        cfg.sitemaps = self.sitemaps.unwrap_or(cfg.sitemaps);
+        cfg.page_robots = self.page_robots.unwrap_or(cfg.page_robots);
+        cfg.keep_duplicates = self.keep_duplicates.unwrap_or(cfg.keep_duplicates);
+        cfg.near_duplicate = self.near_duplicate.or(cfg.near_duplicate);
+        if let Some(host_delays) = self.host_delays {
+            cfg.host_delays.clear();
+            for HostDelay { host, delay } in host_delays {
+                cfg = cfg.host_delay(Regex::new(&host)?, millis(delay));
+            }
+        }
+        if let Some(canonicalizer) = self.canonicalizer {
+            cfg = cfg.canonicalizer(canonicalizer.canonicalizer()?);
+        }
+        if let Some(mime) = self.mime {
+            cfg = cfg.mime(mime);
+        }
+        if let Some(warc) = self.warc {
+            cfg = cfg.warc(warc);
+        }
+        if let Some(budget) = self.budget {
+            cfg = cfg.budget(budget.into());
+        }
+        if let Some(retry) = self.retry {
+            cfg = cfg.retry(retry.into());
+        }
+        Ok(cfg)
+    }
+}
+
+/// Every field of `cfg`, to reproduce it.
+impl From<&SchedulerConfig> for ConfigFile {
+    fn from(cfg: &SchedulerConfig) -> Self {
+        let millis = |duration: Duration| Some(duration.as_millis() as u64);
+        Self {
+            start_urls: None,
+            connection_timeout: millis(cfg.connection_timeout),
+            delay: millis(cfg.delay),
+            global_delay: millis(cfg.global_delay),
+            filter: Some(cfg.filter.as_str().to_owned()),
+            blacklist: Some(cfg.blacklist.as_str().to_owned()),
+            rules: Some(cfg.rules.iter().map(|rule| rule.to_string()).collect()),
+            rule_order: Some(cfg.rule_order),
+            follow: Some(cfg.follow.iter().copied().collect()),
+            assets: Some(cfg.assets.iter().copied().collect()),
+            asset_filter: Some(cfg.asset_filter.as_str().to_owned()),
+            asset_blacklist: Some(cfg.asset_blacklist.as_str().to_owned()),
+            same_host_assets: Some(cfg.same_host_assets),
+            disregard_html: Some(cfg.disregard_html),
+            disregard_other: Some(cfg.disregard_other),
+            raw_html: Some(cfg.raw_html),
+            max_html_size: Some(cfg.max_html_size.unwrap_or(0)),
+            max_body_size: cfg.max_body_size,
+            html_dir: Some(cfg.html_dir.clone()),
+            other_dir: Some(cfg.other_dir.clone()),
+            log_dir: Some(cfg.log_dir.clone()),
+            output: Some(cfg.output),
+            mirror_dir: Some(cfg.mirror_dir.clone()),
+            rewrite_links: Some(cfg.rewrite_links),
+            number_of_rings: cfg.ring.as_ref().map(|ring| ring.max),
+            max_depth: cfg.max_depth,
+            user_agent: Some(cfg.user_agent.clone()),
+            ignore_robots: Some(cfg.ignore_robots),
+            sitemaps: Some(cfg.sitemaps),
+            page_robots: Some(cfg.page_robots),
+            keep_duplicates: Some(cfg.keep_duplicates),
+            near_duplicate: cfg.near_duplicate,
+            host_delays: Some(
+                cfg.host_delays
+                    .iter()
+                    .map(|(host, delay)| HostDelay {
+                        host: host.as_str().to_owned(),
+                        delay: delay.as_millis() as u64,
+                    })
+                    .collect(),
+            ),
+            canonicalizer: Some((&cfg.canonicalizer).into()),
+            mime: Some(MimePolicy::clone(&cfg.mime)),
+            warc: Some(cfg.warc.clone()),
+            budget: Some((&cfg.budget).into()),
+            retry: Some((&cfg.retry).into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostDelay {
+    /// Regex matched against the host.
+    pub host: String,
+    pub delay: u64,
+}
+
+/// [`Canonicalizer`] with the query parameter patterns as strings.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CanonicalizerSection {
+    pub strip_params: Vec<String>,
+    pub sort_query: bool,
+    pub trailing_slash: TrailingSlash,
+    pub prefer_canonical: bool,
+}
+
+impl Default for CanonicalizerSection {
+    fn default() -> Self {
+        (&Canonicalizer::default()).into()
+    }
+}
+
+impl From<&Canonicalizer> for CanonicalizerSection {
+    fn from(canonicalizer: &Canonicalizer) -> Self {
+        Self {
+            strip_params: canonicalizer
+                .strip_params
+                .iter()
+                .map(|pattern| pattern.as_str().to_owned())
+                .collect(),
+            sort_query: canonicalizer.sort_query,
+            trailing_slash: canonicalizer.trailing_slash,
+            prefer_canonical: canonicalizer.prefer_canonical,
+        }
+    }
+}
+
+impl CanonicalizerSection {
+    pub fn canonicalizer(self) -> Result<Canonicalizer> {
+        Ok(Canonicalizer {
+            strip_params: self
+                .strip_params
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<Result<_, _>>()?,
+            sort_query: self.sort_query,
+            trailing_slash: self.trailing_slash,
+            prefer_canonical: self.prefer_canonical,
+        })
+    }
+}
+
+/// [`Budget`] with `max_time` in seconds.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetSection {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_requests: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pages_per_host: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_time: Option<u64>,
+}
+
+impl From<&Budget> for BudgetSection {
+    fn from(budget: &Budget) -> Self {
+        Self {
+            max_requests: budget.max_requests,
+            max_bytes: budget.max_bytes,
+            max_pages_per_host: budget.max_pages_per_host,
+            max_time: budget.max_time.map(|max_time| max_time.as_secs()),
+        }
+    }
+}
+
+impl From<BudgetSection> for Budget {
+    fn from(budget: BudgetSection) -> Self {
+        Self {
+            max_requests: budget.max_requests,
+            max_bytes: budget.max_bytes,
+            max_pages_per_host: budget.max_pages_per_host,
+            max_time: budget.max_time.map(Duration::from_secs),
+        }
+    }
+}
+
+/// [`RetryPolicy`] with the delays in milliseconds.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySection {
+    pub max_attempts: u32,
+    pub delay: u64,
+    pub max_delay: u64,
+}
+
+impl Default for RetrySection {
+    fn default() -> Self {
+        (&RetryPolicy::default()).into()
+    }
+}
+
+impl From<&RetryPolicy> for RetrySection {
+    fn from(retry: &RetryPolicy) -> Self {
+        Self {
+            max_attempts: retry.max_attempts,
+            delay: retry.delay.as_millis() as u64,
+            max_delay: retry.max_delay.as_millis() as u64,
+        }
+    }
+}
+
+impl From<RetrySection> for RetryPolicy {
+    fn from(retry: RetrySection) -> Self {
+        Self {
+            max_attempts: retry.max_attempts,
+            delay: Duration::from_millis(retry.delay),
+            max_delay: Duration::from_millis(retry.max_delay),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::hash::Hasher;
+
+use data_encoding::HEXLOWER;
+use select::{document::Document, predicate::Text};
+use sha1::{Digest as _, Sha1};
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+/// Number of consecutive words hashed together for SimHash.
+const SHINGLE_SIZE: usize = 3;
+
+/// 64-bit FNV-1a, which is stable across runs unlike `DefaultHasher`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// SHA-1 of a body, identifying its content.
+pub type Digest = [u8; 20];
+
+/// The [`Digest`] of `bytes`.
+pub fn sha1(bytes: &[u8]) -> Digest {
+    Sha1::digest(bytes).into()
+}
+
+/// [`sha1`] over bytes given in chunks.
+#[derive(Clone, Debug, Default)]
+pub struct Sha1Hasher(Sha1);
+
+impl Sha1Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub fn finish(self) -> Digest {
+        self.0.finalize().into()
+    }
+}
+
+pub fn to_hex(digest: &Digest) -> String {
+    HEXLOWER.encode(digest)
+}
+
+pub fn from_hex(hex: &str) -> Option<Digest> {
+    HEXLOWER.decode(hex.as_bytes()).ok()?.try_into().ok()
+}
+
+/// [`fnv1a`] over bytes given in chunks.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(FNV_OFFSET_BASIS)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// SimHash of the word shingles in `text`,
+/// so that similar texts have fingerprints differing in few bits,
+/// or `None` if `text` has no words to compare.
+pub fn simhash(text: &str) -> Option<u64> {
+    let words: Vec<_> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE_SIZE.min(words.len()).max(1)) {
+        let hash = fnv1a(shingle.join(" ").as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    let fingerprint = weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |fingerprint, (bit, _)| fingerprint | 1 << bit);
+    Some(fingerprint)
+}
+
+/// SimHash of the text in `document` outside `<script>` and `<style>`.
+pub fn document_simhash(document: &Document) -> Option<u64> {
+    let text: Vec<_> = document
+        .find(Text)
+        .filter(|node| {
+            !node
+                .parent()
+                .and_then(|parent| parent.name())
+                .is_some_and(|name| name == "script" || name == "style")
+        })
+        .filter_map(|node| node.as_text())
+        .collect();
+    simhash(&text.join(" "))
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
@@ -1,71 +1,356 @@
 use anyhow::Result;
 use bytes::Bytes;
+use clap::ValueEnum;
+use encoding_rs::Encoding;
 use log::warn;
 use reqwest::{header::HeaderMap, Url};
-use select::{document::Document, predicate::Name};
-use std::collections::BTreeSet;
+use select::{document::Document, node::Node, predicate::Name};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, path::PathBuf};
+use tokio::fs::remove_file;
+
+use crate::{
+    digest::{document_simhash, sha1, Digest},
+    mime::MimePolicy,
+    robots::product_token,
+};
 
 pub enum FileType {
     Html,
     Other,
 }
 
-pub fn process_headers(headers: &HeaderMap) -> Result<FileType> {
+pub fn process_headers(headers: &HeaderMap, mime: &MimePolicy) -> Result<FileType> {
     let content = match headers.get("content-type") {
         Some(value) => value.to_str()?,
         None => return Ok(FileType::Other),
     };
-    Ok(if content.contains("text/html") {
+    Ok(if mime.parses(content) {
         FileType::Html
     } else {
         FileType::Other
     })
 }
 
-/// `Html(text: String, hrefs: BTreeSet<Url>, imgs: BTreeSet<Url>)`
+/// `Html(page: Page)`
 ///
 /// or
 ///
-/// `Other(extension: String, bytes: Bytes)`
+/// `Other(extension: String, body: Body)`
 #[derive(Debug)]
 pub enum FileContent {
-    Html(String, BTreeSet<Url>, BTreeSet<Url>),
-    Other(String, Bytes),
+    Html(Page),
+    Other(String, Body),
 }
 
-pub fn links_from_html(url: &Url, str: String) -> (String, BTreeSet<Url>, BTreeSet<Url>) {
-    let mut hrefs = BTreeSet::new();
-    let document = Document::from(str.as_str());
-    let a_hrefs: BTreeSet<_> = document
-        .find(Name("a"))
+impl FileContent {
+    /// SHA-1 of the body, or of the decoded text for HTML.
+    pub fn digest(&self) -> Digest {
+        match self {
+            Self::Html(page) => sha1(page.text.as_bytes()),
+            Self::Other(_, body) => body.digest(),
+        }
+    }
+}
+
+/// The body of a non-HTML response.
+#[derive(Debug)]
+pub enum Body {
+    Memory(Bytes),
+    /// Streamed to a temporary file at `path`.
+    File {
+        path: PathBuf,
+        size: u64,
+        digest: Digest,
+    },
+}
+
+impl Body {
+    pub fn size(&self) -> u64 {
+        match self {
+            Self::Memory(bytes) => bytes.len() as u64,
+            Self::File { size, .. } => *size,
+        }
+    }
+
+    /// SHA-1 of the body.
+    pub fn digest(&self) -> Digest {
+        match self {
+            Self::Memory(bytes) => sha1(bytes),
+            Self::File { digest, .. } => *digest,
+        }
+    }
+
+    /// Remove the temporary file, if any.
+    pub async fn discard(self) {
+        if let Self::File { path, .. } = self {
+            if let Err(err) = remove_file(&path).await {
+                warn!("Removing {}: {err}.", path.display());
+            }
+        }
+    }
+}
+
+/// An HTML page and what is found in it.
+#[derive(Debug, Default)]
+pub struct Page {
+    pub text: String,
+    pub links: BTreeSet<Link>,
+    /// The page asks not to be indexed,
+    /// via `<meta name="robots">` or `X-Robots-Tag`.
+    pub noindex: bool,
+    /// The page asks for none of its links to be followed,
+    /// via `<meta name="robots">` or `X-Robots-Tag`.
+    pub nofollow: bool,
+    /// `<link rel="canonical" href>`.
+    pub canonical: Option<Url>,
+    /// SimHash of the text, for near-duplicate detection,
+    /// unless the page has no text.
+    pub simhash: Option<u64>,
+    /// The encoding `text` was decoded from.
+    pub encoding: Option<&'static Encoding>,
+}
+
+impl Page {
+    /// Apply the directives in a `<meta name="robots">` content
+    /// or an `X-Robots-Tag` value, such as `noindex, nofollow`.
+    pub fn add_robots_directives(&mut self, directives: &str) {
+        for directive in directives.split(',') {
+            match directive.trim().to_ascii_lowercase().as_str() {
+                "noindex" => self.noindex = true,
+                "nofollow" => self.nofollow = true,
+                "none" => {
+                    self.noindex = true;
+                    self.nofollow = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Apply an `X-Robots-Tag` value,
+    /// which may only apply to the user-agent it starts with.
+    pub fn add_x_robots_tag(&mut self, value: &str, user_agent: &str) {
+        if let Some((agent, directives)) = value.split_once(':') {
+            let agent = agent.trim();
+            if !agent.contains(',') && !agent.eq_ignore_ascii_case("unavailable_after") {
+                if agent == "*" || agent.eq_ignore_ascii_case(product_token(user_agent)) {
+                    self.add_robots_directives(directives);
+                }
+                return;
+            }
+        }
+        self.add_robots_directives(value);
+    }
+}
+
+/// Where in the HTML a link is found.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkKind {
+    /// `<a href>`.
+    Anchor,
+    /// `<area href>`.
+    Area,
+    /// `<form action>`.
+    Form,
+    /// `<iframe src>` and `<frame src>`.
+    Frame,
+    /// The target of `<meta http-equiv="refresh">`.
+    Refresh,
+    /// `<img src>`, `srcset` and `<video poster>`.
+    Image,
+    /// `<link rel="stylesheet" href>`.
+    Stylesheet,
+    /// `<script src>`.
+    Script,
+    /// `<video src>`, `<audio src>`, `<source src>` and `<track src>`.
+    Media,
+    /// `<object data>` and `<embed src>`.
+    Object,
+    /// `<link href>` with any other `rel`.
+    Link,
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Link {
+    pub url: Url,
+    pub kind: LinkKind,
+    /// The element has `rel="nofollow"`.
+    pub nofollow: bool,
+}
+
+/// `(element, attribute, kind)` for attributes holding one URL.
+const LINK_ATTRIBUTES: [(&str, &str, LinkKind); 14] = [
+    ("a", "href", LinkKind::Anchor),
+    ("area", "href", LinkKind::Area),
+    ("form", "action", LinkKind::Form),
+    ("iframe", "src", LinkKind::Frame),
+    ("frame", "src", LinkKind::Frame),
+    ("img", "src", LinkKind::Image),
+    ("video", "poster", LinkKind::Image),
+    ("script", "src", LinkKind::Script),
+    ("video", "src", LinkKind::Media),
+    ("audio", "src", LinkKind::Media),
+    ("source", "src", LinkKind::Media),
+    ("track", "src", LinkKind::Media),
+    ("object", "data", LinkKind::Object),
+    ("embed", "src", LinkKind::Object),
+];
+
+/// The URL that relative links in `document` resolve against:
+/// the first `<base href>` resolved against `url`, or else `url`.
+pub fn document_base_url(document: &Document, url: &Url) -> Url {
+    let base = document
+        .find(Name("base"))
         .filter_map(|n| n.attr("href"))
-        .collect();
-    for href in a_hrefs {
-        let href_url = url.join(href.split('#').next().unwrap());
-        match href_url {
-            Ok(href_url) => {
-                hrefs.insert(href_url);
+        .next()
+        .and_then(|href| url.join(href.trim()).ok());
+    match base {
+        Some(base) if !matches!(base.scheme(), "data" | "javascript") => base,
+        _ => url.clone(),
+    }
+}
+
+/// The URL and the descriptors of each image candidate in `srcset`,
+/// split as the HTML spec does: a URL runs up to whitespace,
+/// so it may contain commas as `data:` URLs do,
+/// and the descriptors run up to a comma outside parentheses.
+pub fn srcset_candidates(srcset: &str) -> Vec<(&str, &str)> {
+    let bytes = srcset.as_bytes();
+    let mut candidates = Vec::new();
+    let mut index = 0;
+    loop {
+        while index < bytes.len() && (bytes[index].is_ascii_whitespace() || bytes[index] == b',') {
+            index += 1;
+        }
+        if index == bytes.len() {
+            return candidates;
+        }
+        let start = index;
+        while index < bytes.len() && !bytes[index].is_ascii_whitespace() {
+            index += 1;
+        }
+        let url = &srcset[start..index];
+        // Commas ending the URL end the candidate.
+        let trimmed = url.trim_end_matches(',');
+        if trimmed.len() < url.len() {
+            candidates.push((trimmed, ""));
+            continue;
+        }
+        let start = index;
+        let mut in_parens = false;
+        while index < bytes.len() {
+            match bytes[index] {
+                b'(' => in_parens = true,
+                b')' => in_parens = false,
+                b',' if !in_parens => break,
+                _ => {}
+            }
+            index += 1;
+        }
+        candidates.push((url, srcset[start..index].trim()));
+    }
+}
+
+fn has_rel(node: &Node, rel: &str) -> bool {
+    node.attr("rel").is_some_and(|rels| {
+        rels.split_whitespace()
+            .any(|value| value.eq_ignore_ascii_case(rel))
+    })
+}
+
+pub fn links_from_html(url: &Url, str: String) -> Page {
+    let document = Document::from(str.as_str());
+    let base = document_base_url(&document, url);
+    let mut page = Page::default();
+    let mut add = |kind, href: &str, nofollow| match base.join(href.trim()) {
+        Ok(mut href_url) => {
+            if let "http" | "https" = href_url.scheme() {
+                href_url.set_fragment(None);
+                page.links.insert(Link {
+                    url: href_url,
+                    kind,
+                    nofollow,
+                });
+            }
+        }
+        Err(err) => {
+            warn!("{err}.");
+        }
+    };
+    for (name, attribute, kind) in LINK_ATTRIBUTES {
+        for node in document.find(Name(name)) {
+            if let Some(href) = node.attr(attribute) {
+                add(kind, href, has_rel(&node, "nofollow"));
             }
-            Err(err) => {
-                warn!("{err}.");
+        }
+    }
+    for name in ["img", "source"] {
+        for srcset in document.find(Name(name)).filter_map(|n| n.attr("srcset")) {
+            for (src, _) in srcset_candidates(srcset) {
+                add(LinkKind::Image, src, false);
             }
         }
     }
-    let mut imgs = BTreeSet::new();
-    let imgs_src: BTreeSet<_> = document
-        .find(Name("img"))
-        .filter_map(|n| n.attr("src"))
-        .collect();
-    for img in imgs_src {
-        let img_url = url.join(img);
-        match img_url {
-            Ok(img_url0) => {
-                imgs.insert(img_url0);
+    let mut canonical = None;
+    for node in document.find(Name("link")) {
+        let href = match node.attr("href") {
+            Some(href) => href,
+            None => continue,
+        };
+        let nofollow = has_rel(&node, "nofollow");
+        if has_rel(&node, "canonical") {
+            match base.join(href.trim()) {
+                Ok(mut url) => {
+                    url.set_fragment(None);
+                    canonical = Some(url);
+                }
+                Err(err) => warn!("Canonical `{href}`: {err}."),
             }
-            Err(err) => {
-                warn!("{err}.");
+        } else if has_rel(&node, "stylesheet") {
+            add(LinkKind::Stylesheet, href, nofollow);
+        } else if node.attr("rel").is_some() {
+            add(LinkKind::Link, href, nofollow);
+        }
+    }
+    let mut robots_directives = Vec::new();
+    for node in document.find(Name("meta")) {
+        let content = match node.attr("content") {
+            Some(content) => content,
+            None => continue,
+        };
+        let is_refresh = node
+            .attr("http-equiv")
+            .is_some_and(|equiv| equiv.eq_ignore_ascii_case("refresh"));
+        if is_refresh {
+            if let Some(target) = refresh_target(content) {
+                add(LinkKind::Refresh, target, false);
             }
         }
+        if node
+            .attr("name")
+            .is_some_and(|name| name.eq_ignore_ascii_case("robots"))
+        {
+            robots_directives.push(content);
+        }
+    }
+    for directives in robots_directives {
+        page.add_robots_directives(directives);
+    }
+    page.canonical = canonical;
+    page.simhash = document_simhash(&document);
+    page.text = str;
+    page
+}
+
+/// The URL in a refresh `content` such as `5; url='/next'`.
+fn refresh_target(content: &str) -> Option<&str> {
+    let (_, target) = content.split_once([';', ','])?;
+    let target = target.trim_start();
+    if !target.get(..3)?.eq_ignore_ascii_case("url") {
+        return None;
     }
-    (str, hrefs, imgs)
+    let target = target[3..].trim_start().strip_prefix('=')?.trim();
+    Some(target.trim_matches(|c| c == '\'' || c == '"'))
 }
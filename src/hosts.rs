@@ -0,0 +1,96 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use reqwest::Url;
+use tokio::time::Instant;
+
+/// The key URLs are queued by.
+pub fn host_of(url: &Url) -> String {
+    url.host_str().unwrap_or_default().to_owned()
+}
+
+#[derive(Debug)]
+pub struct HostQueue {
+    pub queue: VecDeque<usize>,
+    /// When the next request to this host may be sent.
+    pub next: Instant,
+}
+
+impl Default for HostQueue {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            next: Instant::now(),
+        }
+    }
+}
+
+/// Pending URL ids, queued per host and taken from the hosts in turn.
+#[derive(Debug, Default)]
+pub struct HostQueues {
+    pub hosts: BTreeMap<String, HostQueue>,
+    /// Hosts with URLs queued, in the order they take turns.
+    turns: VecDeque<String>,
+    len: usize,
+}
+
+impl HostQueues {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push_back(&mut self, host: String, url_id: usize) {
+        self.host_queue(host).push_back(url_id);
+        self.len += 1;
+    }
+
+    pub fn push_front(&mut self, host: String, url_id: usize) {
+        self.host_queue(host).push_front(url_id);
+        self.len += 1;
+    }
+
+    fn host_queue(&mut self, host: String) -> &mut VecDeque<usize> {
+        let host_queue = self.hosts.entry(host.clone()).or_default();
+        if host_queue.queue.is_empty() {
+            self.turns.push_back(host);
+        }
+        &mut host_queue.queue
+    }
+
+    /// Pop the first URL id from the first host in turn
+    /// that may be requested at `now`.
+    pub fn pop_ready(&mut self, now: Instant) -> Option<(String, usize)> {
+        let index = self
+            .turns
+            .iter()
+            .position(|host| self.hosts[host].next <= now)?;
+        let host = self.turns.remove(index).unwrap();
+        let host_queue = self.hosts.get_mut(&host).unwrap();
+        let url_id = host_queue.queue.pop_front().unwrap();
+        if !host_queue.queue.is_empty() {
+            self.turns.push_back(host.clone());
+        }
+        self.len -= 1;
+        Some((host, url_id))
+    }
+
+    pub fn is_ready(&self, host: &str, now: Instant) -> bool {
+        self.hosts
+            .get(host)
+            .is_none_or(|host_queue| host_queue.next <= now)
+    }
+
+    /// Do not request `host` again until `next`.
+    pub fn delay_host(&mut self, host: String, next: Instant) {
+        self.hosts.entry(host).or_default().next = next;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.turns
+            .iter()
+            .flat_map(|host| self.hosts[host].queue.iter().copied())
+    }
+}
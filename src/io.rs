@@ -1,8 +1,8 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use tokio::{
-    fs::{create_dir_all, File, OpenOptions},
+    fs::{copy, create_dir_all, remove_file, rename, File, OpenOptions},
     io::AsyncWriteExt,
     spawn,
     task::JoinHandle,
@@ -32,6 +32,36 @@ where
     Ok(())
 }
 
+/// Save to a temporary file first and then rename it,
+/// so that `name` never holds a half-written file.
+pub async fn save_file_atomic<P, B>(name: P, bytes: B) -> Result<()>
+where
+    P: AsRef<Path>,
+    B: AsRef<[u8]>,
+{
+    let name = name.as_ref();
+    let mut temp = name.as_os_str().to_owned();
+    temp.push(".tmp");
+    save_file(&temp, bytes).await?;
+    rename(temp, name).await?;
+    Ok(())
+}
+
+/// Move `from` to `to`, copying it if they are on different file systems.
+pub async fn move_file<P, Q>(from: P, to: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (from, to) = (from.as_ref(), to.as_ref());
+    create_parent_dirs_for(to).await?;
+    if rename(from, to).await.is_err() {
+        copy(from, to).await?;
+        remove_file(from).await?;
+    }
+    Ok(())
+}
+
 pub async fn append_file<P>(name: P) -> Result<File>
 where
     P: AsRef<Path>,
@@ -75,6 +105,18 @@ impl Writer {
         }
     }
 
+    /// Atomically save each of the `files` one after another.
+    pub async fn spawn_all(files: Vec<(PathBuf, Vec<u8>)>) -> Self {
+        Self {
+            handle: spawn(async {
+                for (name, bytes) in files {
+                    save_file_atomic(name, bytes).await?;
+                }
+                Ok(())
+            }),
+        }
+    }
+
     pub async fn wait(self) -> Result<()> {
         self.handle.await?
     }
@@ -3,13 +3,29 @@
 //! Please see README on
 //! [crates.io](https://crates.io/crates/recursive_scraper)
 //! or [GitHub](https://github.com/SichangHe/scraper) for more information.
+pub mod budget;
+pub mod canonical;
+pub mod charset;
 pub mod config;
+pub mod config_file;
+pub mod digest;
 pub mod file;
+pub mod hosts;
 pub mod io;
+pub mod metadata;
 pub mod middle;
+pub mod mime;
+pub mod mirror;
+pub mod recrawl;
+pub mod retry;
+pub mod rewrite;
 pub mod ring;
+pub mod robots;
+pub mod rules;
 pub mod schedule;
+pub mod sitemap;
 pub mod state;
 #[cfg(test)]
 mod test;
 pub mod urls;
+pub mod warc;
@@ -1,30 +1,57 @@
-use std::time::Duration;
+use std::{
+    fs::read_to_string,
+    mem::take,
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use log::debug;
+use log::{debug, error, warn};
 use recursive_scraper::{
-    config::SchedulerConfig,
-    schedule::{client_with_timeout, Scheduler, DEFAULT_TIMEOUT},
+    budget::{Budget, StopReason},
+    canonical::TrailingSlash,
+    config::{Output, SchedulerConfig},
+    config_file::ConfigFile,
+    file::LinkKind,
+    mime::MimePolicy,
+    rules::{Rule, RuleOrder},
+    schedule::Scheduler,
 };
 use regex::Regex;
 use reqwest::Url;
+use tokio::{signal::ctrl_c, spawn};
+
+/// Exit status when the scraper is stopped by a signal.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
-    let start_urls: Vec<_> = args
-        .start_urls
-        .split(',')
-        .map(|str| Url::parse(str).unwrap())
-        .collect();
-    let timeout = match args.connection_timeout {
-        Some(timeout) => Duration::from_millis(timeout),
-        None => DEFAULT_TIMEOUT,
-    };
-    let client = client_with_timeout(timeout);
     let mut cfg = SchedulerConfig::default();
+    let mut start_urls = None;
+    if let Some(config) = args.config {
+        let mut config_file: ConfigFile = toml::from_str(&read_to_string(&config)?)
+            .map_err(|err| anyhow!("Config file `{config}`: {err}"))?;
+        start_urls = config_file.start_urls.take();
+        cfg = config_file.apply(cfg)?;
+    }
+    if let Some(cli_start_urls) = args.start_urls {
+        start_urls = Some(cli_start_urls.split(',').map(str::to_owned).collect());
+    }
+    let start_urls = match start_urls {
+        Some(start_urls) => start_urls,
+        None if args.explain_url.is_some() => Vec::new(),
+        None => bail!("No start URLs given on the command line or in the config file."),
+    };
+    if let Some(timeout) = args.connection_timeout {
+        cfg = cfg.connection_timeout(Duration::from_millis(timeout));
+    }
     if let Some(blacklist) = args.blacklist {
         let blacklist = Regex::new(&blacklist)?;
         cfg = cfg.blacklist(blacklist);
@@ -33,10 +60,86 @@ async fn main() -> Result<()> {
         let filter = Regex::new(&filter)?;
         cfg = cfg.filter(filter);
     }
+    if !args.rule.is_empty() {
+        cfg.rules = args
+            .rule
+            .iter()
+            .map(|rule| rule.parse::<Rule>())
+            .collect::<Result<_>>()?;
+    }
+    if let Some(rule_order) = args.rule_order {
+        cfg = cfg.rule_order(rule_order);
+    }
+    let mut canonicalizer = take(&mut cfg.canonicalizer);
+    if !args.strip_param.is_empty() {
+        canonicalizer.strip_params = args
+            .strip_param
+            .iter()
+            .map(|strip_param| Regex::new(strip_param))
+            .collect::<Result<_, _>>()?;
+    }
+    if let Some(sort_query) = flag(args.sort_query, args.no_sort_query) {
+        canonicalizer.sort_query = sort_query;
+    }
+    if let Some(trailing_slash) = args.trailing_slash {
+        canonicalizer.trailing_slash = trailing_slash;
+    }
+    if let Some(prefer_canonical) = flag(args.prefer_canonical, args.no_prefer_canonical) {
+        canonicalizer.prefer_canonical = prefer_canonical;
+    }
+    cfg = cfg.canonicalizer(canonicalizer);
+    let budget = Budget {
+        max_requests: args.max_requests.or(cfg.budget.max_requests),
+        max_bytes: args.max_bytes.or(cfg.budget.max_bytes),
+        max_pages_per_host: args.max_pages_per_host.or(cfg.budget.max_pages_per_host),
+        max_time: args
+            .max_time
+            .map(Duration::from_secs)
+            .or(cfg.budget.max_time),
+    };
+    cfg = cfg.budget(budget);
+    let mut retry = cfg.retry.clone();
+    if let Some(max_attempts) = args.max_attempts {
+        retry.max_attempts = max_attempts;
+    }
+    if let Some(retry_delay) = args.retry_delay {
+        retry.delay = Duration::from_millis(retry_delay);
+    }
+    if let Some(max_retry_delay) = args.max_retry_delay {
+        retry.max_delay = Duration::from_millis(max_retry_delay);
+    }
+    cfg = cfg.retry(retry);
+    if let Some(follow) = args.follow {
+        cfg = cfg.follow(follow.into_iter().collect());
+    }
+    if let Some(assets) = args.assets {
+        cfg = cfg.assets(assets.into_iter().collect());
+    }
+    if let Some(asset_filter) = args.asset_filter {
+        cfg = cfg.asset_filter(Regex::new(&asset_filter)?);
+    }
+    if let Some(asset_blacklist) = args.asset_blacklist {
+        cfg = cfg.asset_blacklist(Regex::new(&asset_blacklist)?);
+    }
+    if let Some(same_host_assets) = flag(args.same_host_assets, args.no_same_host_assets) {
+        cfg.same_host_assets = same_host_assets;
+    }
     if let Some(delay) = args.delay {
         let delay = Duration::from_millis(delay);
         cfg = cfg.delay(delay);
     }
+    if let Some(global_delay) = args.global_delay {
+        cfg = cfg.global_delay(Duration::from_millis(global_delay));
+    }
+    if !args.host_delay.is_empty() {
+        cfg.host_delays.clear();
+    }
+    for host_delay in args.host_delay {
+        let (host, delay) = host_delay
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow!("Host delay `{host_delay}` is not `REGEX=DELAY`."))?;
+        cfg = cfg.host_delay(Regex::new(host)?, Duration::from_millis(delay.parse()?));
+    }
     if let Some(html_dir) = args.html_dir {
         cfg = cfg.html_dir(html_dir);
     }
@@ -46,22 +149,148 @@ async fn main() -> Result<()> {
     if let Some(log_dir) = args.log_dir {
         cfg = cfg.log_dir(log_dir)
     }
-    if args.disregard_html {
-        cfg = cfg.disregard_html();
+    if let Some(output) = args.output {
+        cfg = cfg.output(output);
+    }
+    if let Some(mirror_dir) = args.mirror_dir {
+        cfg = cfg.mirror_dir(mirror_dir);
+    }
+    let mut warc = cfg.warc.clone();
+    if let Some(warc_dir) = args.warc_dir {
+        warc.dir = warc_dir;
+    }
+    if let Some(gzip) = flag(args.warc_gzip, args.no_warc_gzip) {
+        warc.gzip = gzip;
     }
-    if args.disregard_other {
-        cfg = cfg.disregard_other();
+    if let Some(warc_max_size) = args.warc_max_size {
+        warc.max_size = warc_max_size;
+    }
+    cfg = cfg.warc(warc);
+    if let Some(rewrite_links) = flag(args.rewrite_links, args.no_rewrite_links) {
+        cfg.rewrite_links = rewrite_links;
+    }
+    let mut mime = MimePolicy::clone(&cfg.mime);
+    if !args.parse_type.is_empty() {
+        mime.parse = args.parse_type;
+    }
+    if !args.save_type.is_empty() {
+        mime.save = args.save_type;
+    }
+    if !args.reject_type.is_empty() {
+        mime.reject = args.reject_type;
+    }
+    cfg = cfg.mime(mime);
+    if let Some(max_html_size) = args.max_html_size {
+        cfg = cfg.max_html_size(max_html_size);
+    }
+    if let Some(max_body_size) = args.max_body_size {
+        cfg = cfg.max_body_size(max_body_size);
+    }
+    if let Some(raw_html) = flag(args.raw_html, args.no_raw_html) {
+        cfg.raw_html = raw_html;
+    }
+    if let Some(disregard_html) = flag(args.disregard_html, args.no_disregard_html) {
+        cfg.disregard_html = disregard_html;
+    }
+    if let Some(disregard_other) = flag(args.disregard_other, args.no_disregard_other) {
+        cfg.disregard_other = disregard_other;
     }
     if let Some(number_of_rings) = args.number_of_rings {
         cfg = cfg.with_number_of_rings(number_of_rings);
     }
-    let mut scheduler = Scheduler::from_client(client, cfg);
+    if let Some(max_depth) = args.max_depth {
+        cfg = cfg.max_depth(max_depth);
+    }
+    if let Some(user_agent) = args.user_agent {
+        cfg = cfg.user_agent(user_agent);
+    }
+    if let Some(ignore_robots) = flag(args.ignore_robots, args.no_ignore_robots) {
+        cfg.ignore_robots = ignore_robots;
+    }
+    if let Some(sitemaps) = flag(args.sitemaps, args.no_sitemaps) {
+        cfg.sitemaps = sitemaps;
+    }
+    if let Some(page_robots) = flag(args.page_robots, args.no_page_robots) {
+        cfg.page_robots = page_robots;
+    }
+    if let Some(keep_duplicates) = flag(args.keep_duplicates, args.no_keep_duplicates) {
+        cfg.keep_duplicates = keep_duplicates;
+    }
+    if let Some(near_duplicate) = args.near_duplicate {
+        cfg = cfg.near_duplicate(near_duplicate);
+    }
+    if args.dump_config {
+        let mut config_file = ConfigFile::from(&cfg);
+        config_file.start_urls = Some(start_urls);
+        print!("{}", toml::to_string(&config_file)?);
+        return Ok(());
+    }
+    let start_urls = start_urls
+        .iter()
+        .map(|url| Url::parse(url))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut scheduler = Scheduler::new(cfg);
+    if let Some(explain_url) = args.explain_url {
+        let (url, decision) = scheduler.explain_url(Url::parse(&explain_url)?);
+        println!("{url} is {decision}.");
+        return Ok(());
+    }
+    if args.resume {
+        scheduler.resume().await?;
+    }
+    if args.recrawl {
+        scheduler.recrawl().await?;
+    }
 
     for url in start_urls {
         scheduler.add_pending(url);
     }
+    spawn(stop_on_signal(scheduler.stop_handle()));
     debug!("Starting with {scheduler:#?}.");
     scheduler.recursion().await;
+    if scheduler.stopped_by() == Some(StopReason::Signal) {
+        exit(INTERRUPTED_EXIT_CODE);
+    }
+    Ok(())
+}
+
+/// The value of a boolean flag given as `--flag` or `--no-flag`, if given.
+fn flag(yes: bool, no: bool) -> Option<bool> {
+    match (yes, no) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// On the first SIGINT or SIGTERM, stop the scheduler so it drains.
+/// On the second, exit immediately.
+async fn stop_on_signal(stop: Arc<AtomicBool>) {
+    if let Err(err) = signal().await {
+        error!("Listening for signals: {err}.");
+        return;
+    }
+    warn!("Received signal, finishing requests in flight. Signal again to exit immediately.");
+    stop.store(true, Ordering::Relaxed);
+    if signal().await.is_ok() {
+        exit(INTERRUPTED_EXIT_CODE);
+    }
+}
+
+#[cfg(unix)]
+async fn signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn signal() -> Result<()> {
+    ctrl_c().await?;
     Ok(())
 }
 
@@ -75,10 +304,29 @@ or other directories if specified.
 See <https://github.com/SichangHe/scraper> for more instructions."
 )]
 struct Args {
-    #[clap(help = "The URLs to start scraping from, separated by commas.")]
-    start_urls: String,
+    #[clap(
+        help = "The URLs to start scraping from, separated by commas. Overrides `start_urls` in the config file."
+    )]
+    start_urls: Option<String>,
+    #[clap(
+        short,
+        long,
+        value_delimiter = ',',
+        hide_possible_values = true,
+        help = "Kinds of links to save as assets, separated by commas [default: image]."
+    )]
+    assets: Option<Vec<LinkKind>>,
+    #[clap(long, help = "Regex to match assets that should be excluded.")]
+    asset_blacklist: Option<String>,
+    #[clap(long, help = "Regex to match assets that should be included.")]
+    asset_filter: Option<String>,
     #[clap(short, long, help = "Regex to match URLs that should be excluded.")]
     blacklist: Option<String>,
+    #[clap(
+        long,
+        help = "TOML file to read the configuration from. Flags given override its values."
+    )]
+    config: Option<String>,
     #[clap(
         short,
         long,
@@ -88,25 +336,337 @@ struct Args {
     #[clap(
         short,
         long,
-        help = "Delay between each request in integer milliseconds"
+        help = "Delay between each request to the same host in integer milliseconds [default: 500]. The delay overall is --global-delay."
     )]
     delay: Option<u64>,
+    #[clap(
+        long,
+        action,
+        help = "Print the configuration in effect as TOML, to use with --config, and exit."
+    )]
+    dump_config: bool,
+    #[clap(
+        long,
+        help = "Print whether a URL would be followed, and which rule decided it, and exit."
+    )]
+    explain_url: Option<String>,
     #[clap(short, long, help = "Regex to match URLs that should be included.")]
     filter: Option<String>,
-    #[clap(short = 'i', long, action, help = "Do not save HTMLs.")]
+    #[clap(
+        long,
+        value_delimiter = ',',
+        hide_possible_values = true,
+        help = "Kinds of links to follow, separated by commas [default: anchor,area,frame,refresh]."
+    )]
+    follow: Option<Vec<LinkKind>>,
+    #[clap(
+        short,
+        long,
+        help = "Delay between each request overall in integer milliseconds [default: 500]."
+    )]
+    global_delay: Option<u64>,
+    #[clap(
+        long,
+        help = "Delay in integer milliseconds for hosts matching a regex, as `REGEX=DELAY`. Can be repeated."
+    )]
+    host_delay: Vec<String>,
+    #[clap(
+        short = 'i',
+        long,
+        action,
+        overrides_with = "no_disregard_html",
+        help = "Do not save HTMLs."
+    )]
     disregard_html: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "disregard_html",
+        help = "Save HTMLs, overriding the config file."
+    )]
+    no_disregard_html: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_ignore_robots",
+        help = "Do not fetch or obey robots.txt."
+    )]
+    ignore_robots: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "ignore_robots",
+        help = "Fetch and obey robots.txt, overriding the config file."
+    )]
+    no_ignore_robots: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_keep_duplicates",
+        help = "Save pages and assets even if the same content is already saved."
+    )]
+    keep_duplicates: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "keep_duplicates",
+        help = "Skip pages and assets whose content is already saved, overriding the config file."
+    )]
+    no_keep_duplicates: bool,
     #[clap(short, long, help = "Directory to output the log.")]
     log_dir: Option<String>,
+    #[clap(
+        long,
+        help = "Number of requests for a URL before giving up, including the first [default: 3]."
+    )]
+    max_attempts: Option<u32>,
+    #[clap(
+        long,
+        help = "Abort and record non-HTMLs larger than this many bytes, or 0 for no limit."
+    )]
+    max_body_size: Option<u64>,
+    #[clap(long, help = "Stop after downloading this many bytes.")]
+    max_bytes: Option<u64>,
+    #[clap(
+        long,
+        help = "Do not follow links more than this many hops from the start URLs."
+    )]
+    max_depth: Option<usize>,
+    #[clap(
+        long,
+        help = "Abort and record HTMLs larger than this many bytes, or 0 for no limit [default: 33554432]."
+    )]
+    max_html_size: Option<u64>,
+    #[clap(
+        long,
+        help = "Request at most this many pages and assets from each host."
+    )]
+    max_pages_per_host: Option<usize>,
+    #[clap(long, help = "Stop after requesting this many pages and assets.")]
+    max_requests: Option<usize>,
+    #[clap(
+        long,
+        help = "Maximum delay before retrying a URL in integer milliseconds [default: 60000]."
+    )]
+    max_retry_delay: Option<u64>,
+    #[clap(long, help = "Stop after this many seconds.")]
+    max_time: Option<u64>,
+    #[clap(long, help = "Directory to save the mirror [default: mirror].")]
+    mirror_dir: Option<String>,
     #[clap(short, long, help = "Directory to save non-HTMLs.")]
     other_dir: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "How to save pages and assets [default: directories]."
+    )]
+    output: Option<Output>,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_page_robots",
+        help = "Obey rel=\"nofollow\", <meta name=\"robots\"> and X-Robots-Tag."
+    )]
+    page_robots: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "page_robots",
+        help = "Ignore rel=\"nofollow\", <meta name=\"robots\"> and X-Robots-Tag, overriding the config file."
+    )]
+    no_page_robots: bool,
+    #[clap(
+        long,
+        help = "Content type to parse for links, such as `application/xhtml+xml`, with `*` as a wildcard. Can be repeated [default: text/html]."
+    )]
+    parse_type: Vec<String>,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_prefer_canonical",
+        help = "Treat the <link rel=\"canonical\"> of a page as its URL."
+    )]
+    prefer_canonical: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "prefer_canonical",
+        help = "Do not treat the <link rel=\"canonical\"> of a page as its URL, overriding the config file."
+    )]
+    no_prefer_canonical: bool,
+    #[clap(
+        long,
+        help = "Skip pages whose text SimHash differs from a saved page's in at most this many bits."
+    )]
+    near_duplicate: Option<u32>,
     #[clap(
         short = 'r',
         long,
         help = "Set the number of rings for the URLs outside the filter."
     )]
     number_of_rings: Option<u8>,
-    #[clap(short = 's', long, action, help = "Do not save non-HTMLs.")]
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_raw_html",
+        help = "Save HTMLs as the bytes received instead of decoded to UTF-8."
+    )]
+    raw_html: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "raw_html",
+        help = "Save HTMLs decoded to UTF-8, overriding the config file."
+    )]
+    no_raw_html: bool,
+    #[clap(
+        long,
+        action,
+        conflicts_with = "resume",
+        help = "Crawl again over the record in the log directory, skipping unchanged URLs, and report the changes."
+    )]
+    recrawl: bool,
+    #[clap(
+        long,
+        help = "Content type not to save or download unless parsed, such as `video/*`. Can be repeated."
+    )]
+    reject_type: Vec<String>,
+    #[clap(
+        long,
+        action,
+        help = "Resume from the record and queue in the log directory."
+    )]
+    resume: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_rewrite_links",
+        help = "After scraping, point the links in saved HTMLs at the saved files for offline browsing."
+    )]
+    rewrite_links: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "rewrite_links",
+        help = "Do not rewrite links, overriding the config file."
+    )]
+    no_rewrite_links: bool,
+    #[clap(
+        long,
+        help = "Delay before the first retry of a URL in integer milliseconds, doubled for each retry after [default: 1000]."
+    )]
+    retry_delay: Option<u64>,
+    #[clap(
+        long,
+        help = "Rule to include or exclude URLs, as `ACTION:KIND:PATTERN`, such as `exclude:glob:*.pdf`. Can be repeated."
+    )]
+    rule: Vec<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Whether the first or the last matching rule decides [default: first-match]."
+    )]
+    rule_order: Option<RuleOrder>,
+    #[clap(
+        short = 's',
+        long,
+        action,
+        overrides_with = "no_disregard_other",
+        help = "Do not save non-HTMLs."
+    )]
     disregard_other: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "disregard_other",
+        help = "Save non-HTMLs, overriding the config file."
+    )]
+    no_disregard_other: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_same_host_assets",
+        help = "Only request assets on the same host as the page they are found on."
+    )]
+    same_host_assets: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "same_host_assets",
+        help = "Request assets on any host, overriding the config file."
+    )]
+    no_same_host_assets: bool,
+    #[clap(
+        long,
+        help = "Content type to save, such as `image/*`, if only some are to be saved. Can be repeated."
+    )]
+    save_type: Vec<String>,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_sitemaps",
+        help = "Discover sitemaps from robots.txt and `/sitemap.xml` and add the URLs they list."
+    )]
+    sitemaps: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "sitemaps",
+        help = "Do not discover sitemaps, overriding the config file."
+    )]
+    no_sitemaps: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_sort_query",
+        help = "Sort query parameters before deduplicating URLs."
+    )]
+    sort_query: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "sort_query",
+        help = "Keep the order of query parameters, overriding the config file."
+    )]
+    no_sort_query: bool,
+    #[clap(
+        long,
+        help = "Regex to match names of query parameters to remove, such as `^utm_`. Can be repeated."
+    )]
+    strip_param: Vec<String>,
     #[clap(short = 't', long, help = "Directory to save HTMLs.")]
     html_dir: Option<String>,
+    #[clap(
+        long,
+        value_enum,
+        help = "Add or remove trailing slashes before deduplicating URLs [default: keep]."
+    )]
+    trailing_slash: Option<TrailingSlash>,
+    #[clap(long, help = "Directory to save WARC files [default: warc].")]
+    warc_dir: Option<String>,
+    #[clap(
+        long,
+        action,
+        overrides_with = "no_warc_gzip",
+        help = "Compress each WARC record with gzip."
+    )]
+    warc_gzip: bool,
+    #[clap(
+        long,
+        action,
+        overrides_with = "warc_gzip",
+        help = "Do not compress WARC records, overriding the config file."
+    )]
+    no_warc_gzip: bool,
+    #[clap(
+        long,
+        help = "Size in bytes after which a new WARC file is started [default: 1073741824]."
+    )]
+    warc_max_size: Option<u64>,
+    #[clap(
+        short,
+        long,
+        help = "User-agent sent with requests and matched against robots.txt."
+    )]
+    user_agent: Option<String>,
 }
@@ -0,0 +1,112 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use humantime::format_rfc3339_seconds;
+
+use reqwest::{
+    header::{CONTENT_TYPE, ETAG, LAST_MODIFIED},
+    Response,
+};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+/// What happened when a URL was last requested.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_length: Option<u64>,
+    /// Bytes of the body received, before HTML is decoded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The URL after redirections, as the server gave it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub final_url: Option<String>,
+    /// Encoding HTML was decoded from, such as `Shift_JIS`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// Validators for conditional requests when re-crawling.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    /// SHA-1 of the body downloaded, in hexadecimal.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Milliseconds from sending the request to receiving the headers.
+    pub response_time: u64,
+    /// RFC 3339 time the request was sent, in UTC.
+    pub fetched_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(fetched_at: SystemTime, response_time: Duration) -> Self {
+        Self {
+            response_time: response_time.as_millis() as u64,
+            fetched_at: rfc3339(fetched_at),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_response(self, response: &Response) -> Self {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned)
+        };
+        Self {
+            status: Some(response.status().as_u16()),
+            content_type: header(CONTENT_TYPE),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            content_length: response.content_length(),
+            final_url: Some(response.url().to_string()),
+            ..self
+        }
+    }
+}
+
+/// [`Metadata`] of each URL id, written as `metadata.toml`.
+#[derive(Debug, Default)]
+pub struct MetadataTable(pub BTreeMap<usize, Metadata>);
+
+impl Serialize for MetadataTable {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let table: BTreeMap<_, _> = self
+            .0
+            .iter()
+            .map(|(id, metadata)| (id.to_string(), metadata))
+            .collect();
+        table.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MetadataTable {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let table = BTreeMap::<String, Metadata>::deserialize(deserializer)?;
+        let mut metadata = BTreeMap::new();
+        for (id, entry) in table {
+            metadata.insert(id.parse().map_err(D::Error::custom)?, entry);
+        }
+        Ok(Self(metadata))
+    }
+}
+
+/// `time` as `YYYY-MM-DDTHH:MM:SSZ`.
+pub fn rfc3339(time: SystemTime) -> String {
+    format_rfc3339_seconds(time).to_string()
+}
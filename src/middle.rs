@@ -1,62 +1,213 @@
 use anyhow::{Error, Result};
-use reqwest::{RequestBuilder, Response, Url};
-use tokio::{spawn, task::JoinHandle};
+use bytes::Bytes;
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE},
+    RequestBuilder, Response, StatusCode, Url, Version,
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+use tokio::{fs::remove_file, io::AsyncWriteExt, spawn, task::JoinHandle};
 
-use crate::file::{links_from_html, process_headers, FileContent, FileType};
+use crate::{
+    charset::decode_html,
+    digest::Sha1Hasher,
+    file::{links_from_html, process_headers, Body, FileContent, FileType},
+    io::create_file,
+    metadata::Metadata,
+    mime::{extension, MimePolicy},
+    retry::StatusError,
+};
 
-pub type Request = JoinHandle<(usize, Result<Response>)>;
+pub type Request = JoinHandle<(usize, Result<Response>, Metadata)>;
 
 pub async fn spawn_request(url_id: usize, request: RequestBuilder) -> Request {
     spawn(async move {
-        (
-            url_id,
-            match request.send().await {
-                Ok(response) => Ok(response),
-                Err(err) => Err(Error::from(err)),
-            },
-        )
+        let fetched_at = SystemTime::now();
+        let start = Instant::now();
+        let result = request.send().await;
+        let metadata = Metadata::new(fetched_at, start.elapsed());
+        match result {
+            Ok(response) => {
+                let metadata = metadata.with_response(&response);
+                (url_id, Ok(response), metadata)
+            }
+            Err(err) => {
+                let metadata = Metadata {
+                    error: Some(err.to_string()),
+                    ..metadata
+                };
+                (url_id, Err(Error::from(err)), metadata)
+            }
+        }
     })
 }
 
-async fn process_response(response: Response) -> Result<FileContent> {
+/// Largest HTML body read into memory to parse, by default.
+pub const DEFAULT_MAX_HTML_SIZE: u64 = 32 << 20;
+
+/// What processing a response needs from the configuration.
+#[derive(Clone, Debug)]
+pub struct ProcessConfig {
+    pub user_agent: String,
+    /// Shared with the scheduler, rather than cloned for each request.
+    pub mime: Arc<MimePolicy>,
+    /// Largest HTML body read into memory.
+    pub max_html_size: Option<u64>,
+    /// Largest non-HTML body downloaded.
+    pub max_body_size: Option<u64>,
+    /// Directory to stream non-HTML bodies into,
+    /// or `None` to keep them in memory.
+    pub download_dir: Option<PathBuf>,
+}
+
+/// The body is larger than allowed, so its download is aborted.
+#[derive(Debug)]
+pub struct TooLarge {
+    /// Size announced in `Content-Length`, or received so far.
+    pub size: u64,
+    pub limit: u64,
+}
+
+impl Display for TooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "body of at least {} bytes is over the limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+fn check_size(size: u64, limit: Option<u64>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(TooLarge { size, limit }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Read the body into memory, up to `limit` bytes.
+pub async fn read_body(response: &mut Response, limit: Option<u64>) -> Result<Bytes> {
+    check_size(response.content_length().unwrap_or_default(), limit)?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        check_size(body.len() as u64, limit)?;
+    }
+    Ok(body.into())
+}
+
+/// Stream the body to `path` chunk by chunk, up to `limit` bytes.
+/// The file is removed if the download fails.
+async fn download_body(response: &mut Response, path: PathBuf, limit: Option<u64>) -> Result<Body> {
+    check_size(response.content_length().unwrap_or_default(), limit)?;
+    let result: Result<Body> = async {
+        let mut file = create_file(&path).await?;
+        let mut hasher = Sha1Hasher::default();
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await? {
+            size += chunk.len() as u64;
+            check_size(size, limit)?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(Body::File {
+            path: path.clone(),
+            size,
+            digest: hasher.finish(),
+        })
+    }
+    .await;
+    if result.is_err() {
+        _ = remove_file(&path).await;
+    }
+    result
+}
+
+async fn process_response(
+    url_id: usize,
+    mut response: Response,
+    cfg: ProcessConfig,
+) -> Result<(FileContent, Exchange)> {
     let status = response.status();
     if !status.is_success() {
-        return Err(Error::msg(format!("status code error: {status}")));
+        return Err(StatusError::new(status, response.headers()).into());
     }
     let final_url = response.url().to_owned();
-    let url_str = clean_url(&final_url);
-    let headers = response.headers();
-    let file_type = process_headers(headers)?;
+    let version = response.version();
+    let headers = response.headers().to_owned();
+    let file_type = process_headers(&headers, &cfg.mime)?;
     let content;
+    let exchange_body;
     if let FileType::Html = file_type {
-        let (text, hrefs, imgs) = links_from_html(&final_url, response.text().await?);
-        content = FileContent::Html(text, hrefs, imgs);
+        let body = read_body(&mut response, cfg.max_html_size).await?;
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let (text, encoding) = decode_html(&body, content_type);
+        let mut page = links_from_html(&final_url, text);
+        page.encoding = Some(encoding);
+        for value in headers
+            .get_all("x-robots-tag")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+        {
+            page.add_x_robots_tag(value, &cfg.user_agent);
+        }
+        content = FileContent::Html(page);
+        exchange_body = body;
     } else {
-        let extension = ".".to_owned()
-            + url_str
-                .split('.')
-                .last()
-                .unwrap()
-                .split('/')
-                .last()
-                .unwrap();
-        content = FileContent::Other(extension, response.bytes().await?);
+        let extension = extension(&headers, &final_url);
+        let body = match &cfg.download_dir {
+            Some(dir) => {
+                let path = dir.join(format!("{url_id}.part"));
+                download_body(&mut response, path, cfg.max_body_size).await?
+            }
+            None => Body::Memory(read_body(&mut response, cfg.max_body_size).await?),
+        };
+        exchange_body = match &body {
+            Body::Memory(bytes) => bytes.clone(),
+            Body::File { .. } => Bytes::new(),
+        };
+        content = FileContent::Other(extension, body);
     }
-    Ok(content)
+    let exchange = Exchange {
+        url: final_url,
+        version,
+        status,
+        headers,
+        body: exchange_body,
+    };
+    Ok((content, exchange))
 }
 
-fn clean_url(url: &Url) -> String {
-    url.to_string().split('#').next().unwrap().to_owned()
-}
+pub type Process = JoinHandle<(usize, Result<(FileContent, Exchange)>)>;
 
-pub type Process = JoinHandle<(usize, Result<FileContent>)>;
+pub async fn spawn_process(url_id: usize, response: Response, cfg: ProcessConfig) -> Process {
+    spawn(async move { (url_id, process_response(url_id, response, cfg).await) })
+}
 
-pub async fn spawn_process(url_id: usize, response: Response) -> Process {
-    spawn(async move { (url_id, process_response(response).await) })
+/// The response as received, for WARC records.
+#[derive(Debug)]
+pub struct Exchange {
+    /// The URL after redirections.
+    pub url: Url,
+    pub version: Version,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// Empty if the body was streamed to disk.
+    pub body: Bytes,
 }
 
 #[derive(Debug)]
 pub struct Conclusion {
     pub url_id: usize,
     pub content: FileContent,
+    pub exchange: Exchange,
 }
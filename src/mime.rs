@@ -0,0 +1,246 @@
+use reqwest::{
+    header::{HeaderMap, CONTENT_DISPOSITION, CONTENT_TYPE},
+    Url,
+};
+use serde::{Deserialize, Serialize};
+
+/// Longest extension kept, without the dot.
+const MAX_EXTENSION_LEN: usize = 10;
+
+/// Extension for each MIME type, without the dot.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("application/atom+xml", "atom"),
+    ("application/gzip", "gz"),
+    ("application/javascript", "js"),
+    ("application/json", "json"),
+    ("application/ld+json", "jsonld"),
+    ("application/manifest+json", "webmanifest"),
+    ("application/msword", "doc"),
+    ("application/ogg", "ogx"),
+    ("application/pdf", "pdf"),
+    ("application/rss+xml", "rss"),
+    ("application/rtf", "rtf"),
+    ("application/vnd.ms-excel", "xls"),
+    ("application/vnd.ms-fontobject", "eot"),
+    ("application/vnd.ms-powerpoint", "ppt"),
+    (
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "pptx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "xlsx",
+    ),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "docx",
+    ),
+    ("application/wasm", "wasm"),
+    ("application/x-bzip2", "bz2"),
+    ("application/x-gzip", "gz"),
+    ("application/x-javascript", "js"),
+    ("application/x-tar", "tar"),
+    ("application/x-7z-compressed", "7z"),
+    ("application/xhtml+xml", "xhtml"),
+    ("application/xml", "xml"),
+    ("application/zip", "zip"),
+    ("audio/aac", "aac"),
+    ("audio/flac", "flac"),
+    ("audio/mp4", "m4a"),
+    ("audio/mpeg", "mp3"),
+    ("audio/ogg", "oga"),
+    ("audio/wav", "wav"),
+    ("audio/webm", "weba"),
+    ("font/otf", "otf"),
+    ("font/ttf", "ttf"),
+    ("font/woff", "woff"),
+    ("font/woff2", "woff2"),
+    ("image/avif", "avif"),
+    ("image/bmp", "bmp"),
+    ("image/gif", "gif"),
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/svg+xml", "svg"),
+    ("image/tiff", "tiff"),
+    ("image/vnd.microsoft.icon", "ico"),
+    ("image/webp", "webp"),
+    ("image/x-icon", "ico"),
+    ("text/calendar", "ics"),
+    ("text/css", "css"),
+    ("text/csv", "csv"),
+    ("text/html", "html"),
+    ("text/javascript", "js"),
+    ("text/markdown", "md"),
+    ("text/plain", "txt"),
+    ("text/xml", "xml"),
+    ("video/mp2t", "ts"),
+    ("video/mp4", "mp4"),
+    ("video/mpeg", "mpeg"),
+    ("video/ogg", "ogv"),
+    ("video/quicktime", "mov"),
+    ("video/webm", "webm"),
+    ("video/x-msvideo", "avi"),
+];
+
+/// Which responses are parsed for links, saved, or dropped after their headers,
+/// by glob patterns such as `image/*` matched against their MIME types.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MimePolicy {
+    /// Types parsed as HTML for links to follow.
+    pub parse: Vec<String>,
+    /// Types saved; every type if empty.
+    pub save: Vec<String>,
+    /// Types not saved, taking precedence over `save`.
+    pub reject: Vec<String>,
+}
+
+impl Default for MimePolicy {
+    fn default() -> Self {
+        Self {
+            parse: vec!["text/html".to_owned()],
+            save: Vec::new(),
+            reject: Vec::new(),
+        }
+    }
+}
+
+impl MimePolicy {
+    /// The policy with its patterns in lowercase,
+    /// as [`Self::parses`] and [`Self::saves`] expect.
+    pub fn lowercase(self) -> Self {
+        let lowercase = |patterns: Vec<String>| {
+            patterns
+                .into_iter()
+                .map(|pattern| pattern.to_ascii_lowercase())
+                .collect()
+        };
+        Self {
+            parse: lowercase(self.parse),
+            save: lowercase(self.save),
+            reject: lowercase(self.reject),
+        }
+    }
+
+    /// Whether a response with `content_type` is parsed for links.
+    pub fn parses(&self, content_type: &str) -> bool {
+        let essence = essence(content_type);
+        self.parse
+            .iter()
+            .any(|pattern| glob_match_lowercase(pattern, &essence))
+    }
+
+    /// Whether a response with `content_type` is saved.
+    pub fn saves(&self, content_type: &str) -> bool {
+        let essence = essence(content_type);
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| glob_match_lowercase(pattern, &essence))
+        };
+        (self.save.is_empty() || matches(&self.save)) && !matches(&self.reject)
+    }
+
+    /// Whether the body of a response with `content_type` is needed at all.
+    pub fn downloads(&self, content_type: &str) -> bool {
+        self.parses(content_type) || self.saves(content_type)
+    }
+}
+
+/// Whether `text` matches `pattern`, case-insensitively,
+/// where `*` matches any characters.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    glob_match_lowercase(&pattern.to_ascii_lowercase(), &text.to_ascii_lowercase())
+}
+
+/// [`glob_match`] for `pattern` and `text` already in lowercase.
+pub fn glob_match_lowercase(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match text.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts: Vec<_> = parts.collect();
+    let last = match parts.pop() {
+        Some(last) => last,
+        // No `*`.
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// The extension to save a non-HTML response as, such as `.png`,
+/// or an empty string if none is found.
+///
+/// Found from the `Content-Type`, the `Content-Disposition` filename
+/// or the last segment of the URL path, in this order.
+/// Only ASCII letters and digits are kept,
+/// so the extension cannot lead out of the directory.
+pub fn extension(headers: &HeaderMap, url: &Url) -> String {
+    let from_type = header_str(headers, CONTENT_TYPE.as_str())
+        .and_then(|content_type| extension_for_type(&essence(content_type)));
+    let extension = from_type
+        .map(str::to_owned)
+        .or_else(|| {
+            header_str(headers, CONTENT_DISPOSITION.as_str())
+                .and_then(disposition_filename)
+                .and_then(|filename| extension_of_name(&filename))
+        })
+        .or_else(|| {
+            url.path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .and_then(extension_of_name)
+        });
+    extension
+        .map(|extension| format!(".{extension}"))
+        .unwrap_or_default()
+}
+
+/// The extension for the MIME type `essence`, without the dot.
+pub fn extension_for_type(essence: &str) -> Option<&'static str> {
+    EXTENSIONS
+        .iter()
+        .find(|(mime, _)| *mime == essence)
+        .map(|(_, extension)| *extension)
+}
+
+/// The MIME type without parameters, in lowercase.
+pub fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// The `filename` parameter of `Content-Disposition`.
+fn disposition_filename(disposition: &str) -> Option<String> {
+    disposition.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("filename")
+            .then(|| value.trim().trim_matches('"').to_owned())
+    })
+}
+
+/// The sanitized extension of the file name `name`, without the dot.
+fn extension_of_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let (_, extension) = name.rsplit_once('.')?;
+    let valid = !extension.is_empty()
+        && extension.len() <= MAX_EXTENSION_LEN
+        && extension.bytes().all(|byte| byte.is_ascii_alphanumeric());
+    valid.then(|| extension.to_ascii_lowercase())
+}
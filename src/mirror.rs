@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use reqwest::Url;
+use tokio::fs::{create_dir, metadata, rename};
+
+use crate::digest::fnv1a;
+
+/// Longest file name kept as is; most file systems allow 255 bytes.
+const MAX_NAME_LEN: usize = 200;
+
+/// The path of `url` relative to the mirror directory,
+/// as `host/path/to/page.html` like `wget --mirror`.
+///
+/// Directory URLs get `index.html`.
+/// The query is kept after `@`.
+/// `extension` is `None` for HTML,
+/// which gets `.html` unless it has an `.html` or `.htm` extension,
+/// and otherwise the extension found for the content type, such as `.png`,
+/// which is added if the URL path has no extension.
+/// A file that ends up where a directory is needed moves into it,
+/// see [`make_room`].
+pub fn mirror_path(url: &Url, extension: Option<&str>) -> PathBuf {
+    let mut path = PathBuf::new();
+    let host = url.host_str().unwrap_or("unknown");
+    match url.port() {
+        Some(port) => path.push(escape(&format!("{host}+{port}"))),
+        None => path.push(escape(host)),
+    }
+    let mut segments: Vec<_> = url.path().split('/').skip(1).collect();
+    let last = segments.pop().filter(|last| !last.is_empty());
+    for segment in segments.into_iter().filter(|segment| !segment.is_empty()) {
+        path.push(shorten(escape(segment)));
+    }
+    let last = last.unwrap_or("index.html");
+    let mut name = escape(last);
+    if let Some(query) = url.query() {
+        name = format!("{name}@{}", escape(query));
+    }
+    match extension {
+        None => {
+            let lowercase = name.to_ascii_lowercase();
+            if url.query().is_some()
+                || !(lowercase.ends_with(".html") || lowercase.ends_with(".htm"))
+            {
+                name += ".html";
+            }
+        }
+        Some(extension) => {
+            if Path::new(last).extension().is_none() {
+                name += extension;
+            }
+        }
+    }
+    path.push(shorten(name));
+    path
+}
+
+/// Percent-encode the characters not allowed in file names on common systems.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' | '\0'..='\x1f' => {
+                escaped += &format!("%{:02X}", c as u32)
+            }
+            c => escaped.push(c),
+        }
+    }
+    if escaped == "." || escaped == ".." {
+        escaped = escaped.replace('.', "%2E");
+    }
+    escaped
+}
+
+/// Make room to save a file at `path` in the mirror directory `root`,
+/// since URLs such as `/a` and `/a/b` need `a` as both a file and a directory:
+/// a file saved where a directory is now needed moves into it as `index`,
+/// and a file whose path already is a directory is saved in it as `index`,
+/// keeping the extension of its name.
+/// Returns where to save the file, and the files moved as `(from, to)`.
+pub async fn make_room(root: &Path, path: &Path) -> Result<(PathBuf, Vec<(PathBuf, PathBuf)>)> {
+    let mut moved = Vec::new();
+    let mut dirs: Vec<_> = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| dir.starts_with(root) && *dir != root)
+        .collect();
+    dirs.reverse();
+    for dir in dirs {
+        if metadata(dir).await.is_ok_and(|metadata| metadata.is_file()) {
+            let mut temp = dir.as_os_str().to_owned();
+            temp.push(".tmp");
+            rename(dir, &temp).await?;
+            create_dir(dir).await?;
+            let to = dir.join(index_name(dir));
+            rename(temp, &to).await?;
+            moved.push((dir.to_owned(), to));
+        }
+    }
+    let mut path = path.to_owned();
+    while metadata(&path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir())
+    {
+        path = path.join(index_name(&path));
+    }
+    Ok((path, moved))
+}
+
+/// `index` with the extension of the name of `path`.
+fn index_name(path: &Path) -> PathBuf {
+    let mut name = PathBuf::from("index");
+    if let Some(extension) = path.extension() {
+        name.set_extension(extension);
+    }
+    name
+}
+
+/// Cut `name` if it is too long, keeping its extension
+/// and a hash of the whole name so that different names stay different.
+fn shorten(name: String) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name;
+    }
+    let extension = match name.rsplit_once('.') {
+        Some((_, extension)) if extension.len() <= 8 => format!(".{extension}"),
+        _ => String::new(),
+    };
+    let mut end = MAX_NAME_LEN - 17 - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}-{:016x}{extension}",
+        &name[..end],
+        fnv1a(name.as_bytes())
+    )
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use reqwest::header::{HeaderName, IF_MODIFIED_SINCE, IF_NONE_MATCH};
+use serde::{Deserialize, Serialize};
+
+use crate::{metadata::MetadataTable, urls::Record};
+
+pub const CHANGES_DIR: &str = "changes.toml";
+/// Copies of the record and the metadata of the crawl being re-crawled,
+/// which this crawl overwrites as it goes.
+pub const PREVIOUS_RECORD_DIR: &str = "previous_record.toml";
+pub const PREVIOUS_METADATA_DIR: &str = "previous_metadata.toml";
+
+/// What the previous crawl left in the log directory,
+/// to re-crawl incrementally.
+#[derive(Debug, Default)]
+pub struct PreviousCrawl {
+    pub record: Record,
+    pub metadata: MetadataTable,
+    /// URLs first found on each page.
+    children: BTreeMap<usize, Vec<usize>>,
+}
+
+impl PreviousCrawl {
+    pub fn new(record: Record, metadata: MetadataTable) -> Self {
+        let mut children: BTreeMap<_, Vec<_>> = BTreeMap::new();
+        for (child, parent) in &record.parents {
+            children.entry(*parent).or_default().push(*child);
+        }
+        Self {
+            record,
+            metadata,
+            children,
+        }
+    }
+
+    /// Digest of the body of `url_id`, if it was downloaded.
+    pub fn digest(&self, url_id: usize) -> Option<&str> {
+        self.metadata.0.get(&url_id)?.digest.as_deref()
+    }
+
+    /// `If-None-Match` and `If-Modified-Since` for `url_id`,
+    /// if its body was downloaded and came with validators.
+    pub fn conditional_headers(&self, url_id: usize) -> Vec<(HeaderName, String)> {
+        let mut headers = Vec::new();
+        if let Some(metadata) = self.metadata.0.get(&url_id) {
+            if metadata.digest.is_some() {
+                if let Some(etag) = &metadata.etag {
+                    headers.push((IF_NONE_MATCH, etag.clone()));
+                }
+                if let Some(last_modified) = &metadata.last_modified {
+                    headers.push((IF_MODIFIED_SINCE, last_modified.clone()));
+                }
+            }
+        }
+        headers
+    }
+
+    pub fn children(&self, url_id: usize) -> &[usize] {
+        self.children.get(&url_id).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// How the bodies downloaded in this crawl compare to the previous crawl.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub new: BTreeSet<usize>,
+    pub changed: BTreeSet<usize>,
+    /// Including URLs answered with `304 Not Modified`.
+    pub unchanged: BTreeSet<usize>,
+}
+
+impl Changes {
+    /// Classify `url_id`, whose body now has `digest`.
+    pub fn add(&mut self, url_id: usize, previous: Option<&str>, digest: &str) {
+        match previous {
+            None => self.new.insert(url_id),
+            Some(previous) if previous == digest => self.unchanged.insert(url_id),
+            Some(_) => self.changed.insert(url_id),
+        };
+    }
+
+    /// The report of the URLs in each category,
+    /// with the URLs downloaded in `previous` but not in `record` as vanished,
+    /// or as unreached if `record` stopped early without requesting them.
+    pub fn report(&self, previous: &PreviousCrawl, record: &Record) -> ChangeReport {
+        let urls = |ids: &BTreeSet<usize>| {
+            ids.iter()
+                .filter_map(|id| record.url_ids.get(id))
+                .map(|url| url.to_string())
+                .collect()
+        };
+        let (mut vanished, mut unreached) = (Vec::new(), Vec::new());
+        for (id, metadata) in &previous.metadata.0 {
+            if metadata.digest.is_none()
+                || self.new.contains(id)
+                || self.changed.contains(id)
+                || self.unchanged.contains(id)
+            {
+                continue;
+            }
+            let url = match previous.record.url_ids.get(id) {
+                Some(url) => url.to_string(),
+                None => continue,
+            };
+            if record.stopped_by.is_some() && !record.attempts.contains_key(id) {
+                unreached.push(url);
+            } else {
+                vanished.push(url);
+            }
+        }
+        ChangeReport {
+            new: urls(&self.new),
+            changed: urls(&self.changed),
+            unchanged: urls(&self.unchanged),
+            vanished,
+            unreached,
+        }
+    }
+}
+
+/// Written as `changes.toml` after a re-crawl.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ChangeReport {
+    pub new: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Downloaded in the previous crawl but not in this one.
+    pub vanished: Vec<String>,
+    /// Downloaded in the previous crawl but not requested in this one
+    /// because it stopped early, so not known to have vanished.
+    #[serde(default)]
+    pub unreached: Vec<String>,
+}
@@ -0,0 +1,183 @@
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Error;
+use rand::Rng;
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times, and how long after, failed URLs are requested again.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of requests for a URL before giving up, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each retry after.
+    pub delay: Duration,
+    /// Upper bound of the delay, including `Retry-After`.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            delay: DEFAULT_RETRY_DELAY,
+            max_delay: DEFAULT_MAX_RETRY_DELAY,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying after `attempts` failed requests,
+    /// or `None` if the URL should not be retried.
+    pub fn backoff(&self, attempts: u32, error: &RequestError) -> Option<Duration> {
+        if !error.kind.is_transient() || attempts >= self.max_attempts {
+            return None;
+        }
+        let exponential = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_delay);
+        // Equal jitter: somewhere between half and all of the delay.
+        let half = exponential / 2;
+        let jitter = rand::thread_rng().gen_range(Duration::ZERO..=half);
+        let backoff = half + jitter;
+        Some(match self.retry_after(error) {
+            Some(retry_after) => backoff.max(retry_after),
+            None => backoff,
+        })
+    }
+
+    /// The `Retry-After` of `error`, clamped to `max_delay`
+    /// so that a server cannot hold a host for days.
+    pub fn retry_after(&self, error: &RequestError) -> Option<Duration> {
+        error
+            .retry_after
+            .map(|retry_after| retry_after.min(self.max_delay))
+    }
+}
+
+/// Kinds of failures, which decide whether to retry.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// The request or the response timed out, including status 408.
+    Timeout,
+    /// Could not resolve or connect to the host.
+    Connection,
+    /// Status 429.
+    TooManyRequests,
+    /// Status 5xx.
+    Server,
+    /// Status 4xx other than 408 and 429, which is permanent.
+    Client,
+    /// The response body could not be read.
+    Body,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, Self::Client)
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::REQUEST_TIMEOUT => Self::Timeout,
+            StatusCode::TOO_MANY_REQUESTS => Self::TooManyRequests,
+            status if status.is_server_error() => Self::Server,
+            status if status.is_client_error() => Self::Client,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// A response whose status is not success.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    /// `Retry-After` of a 429 or 503 response.
+    pub retry_after: Option<Duration>,
+}
+
+impl StatusError {
+    pub fn new(status: StatusCode, headers: &HeaderMap) -> Self {
+        let retry_after = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+            _ => None,
+        };
+        Self {
+            status,
+            retry_after,
+        }
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "status code error: {}", self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// `Retry-After` in either delay-seconds or HTTP-date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// A classified failure of a URL.
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    pub retry_after: Option<Duration>,
+}
+
+impl From<&Error> for RequestError {
+    fn from(err: &Error) -> Self {
+        if let Some(StatusError {
+            status,
+            retry_after,
+        }) = err.downcast_ref()
+        {
+            return Self {
+                kind: ErrorKind::from_status(*status),
+                retry_after: *retry_after,
+            };
+        }
+        let kind = match err.downcast_ref::<reqwest::Error>() {
+            Some(err) if err.is_timeout() => ErrorKind::Timeout,
+            Some(err) if err.is_connect() => ErrorKind::Connection,
+            Some(err) if err.is_body() || err.is_decode() => ErrorKind::Body,
+            Some(err) => match err.status() {
+                Some(status) => ErrorKind::from_status(status),
+                None => ErrorKind::Other,
+            },
+            None => ErrorKind::Other,
+        };
+        Self {
+            kind,
+            retry_after: None,
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+use regex::{Captures, Regex};
+use reqwest::Url;
+use select::document::Document;
+
+use crate::{
+    canonical::Canonicalizer,
+    file::{document_base_url, srcset_candidates},
+    urls::Record,
+};
+
+/// A comment, a `<script>` or `<style>` element with its opening tag,
+/// or any other tag, with quoted attribute values that may contain `>`.
+const TAG: &str = r#"(?is)<!--.*?-->|(<script\b(?:[^>"']|"[^"]*"|'[^']*')*>).*?</script\s*>|(<style\b(?:[^>"']|"[^"]*"|'[^']*')*>).*?</style\s*>|<[a-z](?:[^>"']|"[^"]*"|'[^']*')*>"#;
+const BASE_TAG: &str = r"(?i)^<base[\s/>]";
+/// Any attribute with a value, so that attribute names in values are skipped.
+const ATTRIBUTE: &str = r#"(\s([^\s"'>/=]+)\s*=\s*)("[^"]*"|'[^']*'|[^\s"'>]+)"#;
+const LINK_ATTRIBUTES: [&str; 6] = ["href", "src", "action", "poster", "data", "srcset"];
+
+fn regex(cell: &'static OnceLock<Regex>, pattern: &str) -> &'static Regex {
+    cell.get_or_init(|| Regex::new(pattern).unwrap())
+}
+
+/// Local paths of the pages and assets scraped,
+/// to point the links in saved HTML at.
+#[derive(Debug, Default)]
+pub struct LocalPaths {
+    /// By canonicalized URL, including URLs redirected to or canonicalized as
+    /// a saved page.
+    by_url: BTreeMap<Url, PathBuf>,
+    files: BTreeSet<PathBuf>,
+    /// Whether files are saved under paths that follow their URLs,
+    /// so that a relative link to a saved file can be left as is.
+    mirror: bool,
+}
+
+impl LocalPaths {
+    pub fn from_record(record: &Record, mirror: bool) -> Self {
+        let mut local = Self {
+            mirror,
+            ..Self::default()
+        };
+        for (url, id) in &record.urls {
+            let mut id = *id;
+            // Follow redirects and canonical URLs, guarding against cycles.
+            for _ in 0..16 {
+                let next = record.redirects.get(&id).or(record.canonicals.get(&id));
+                match next.or(record.stand_ins.get(&id)) {
+                    Some(next) if !record.paths.contains_key(&id) => id = *next,
+                    _ => break,
+                }
+            }
+            if let Some(path) = record.paths.get(&id) {
+                local.by_url.insert(url.clone(), PathBuf::from(path));
+            }
+        }
+        local.files = record.paths.values().map(PathBuf::from).collect();
+        local
+    }
+
+    /// Rewrite the links in `html`, saved at `path` from `url`,
+    /// to relative paths to the pages and assets saved,
+    /// and the other links to absolute URLs.
+    /// `<base>` is removed since it would break relative paths.
+    ///
+    /// In a mirror, links that already lead to saved files are left as is,
+    /// so rewriting the same page again does not change it.
+    /// Links in comments and inside `<script>` and `<style>` are left as is.
+    pub fn rewrite_html(
+        &self,
+        html: &str,
+        url: &Url,
+        path: &Path,
+        canonicalizer: &Canonicalizer,
+    ) -> String {
+        let base = document_base_url(&Document::from(html), url);
+        let dir = path.parent().unwrap_or(Path::new(""));
+        static TAG_REGEX: OnceLock<Regex> = OnceLock::new();
+        static BASE_TAG_REGEX: OnceLock<Regex> = OnceLock::new();
+        static ATTRIBUTE_REGEX: OnceLock<Regex> = OnceLock::new();
+        let (tag, base_tag, attribute) = (
+            regex(&TAG_REGEX, TAG),
+            regex(&BASE_TAG_REGEX, BASE_TAG),
+            regex(&ATTRIBUTE_REGEX, ATTRIBUTE),
+        );
+        tag.replace_all(html, |captures: &Captures| {
+            let whole = &captures[0];
+            if whole.starts_with("<!--") {
+                return whole.to_owned();
+            }
+            // Only the opening tag of `<script>` and `<style>`.
+            let (tag, rest) = match captures.get(1).or(captures.get(2)) {
+                Some(tag) => whole.split_at(tag.as_str().len()),
+                None => (whole, ""),
+            };
+            if base_tag.is_match(tag) {
+                return String::new();
+            }
+            let tag = attribute
+                .replace_all(tag, |attribute: &Captures| {
+                    let name = &attribute[1];
+                    let key = attribute[2].to_ascii_lowercase();
+                    if !LINK_ATTRIBUTES.contains(&key.as_str()) {
+                        return attribute[0].to_owned();
+                    }
+                    let value = &attribute[3];
+                    let (quote, value) = match value.chars().next() {
+                        Some(quote @ ('"' | '\'')) => {
+                            (quote.to_string(), &value[1..value.len() - 1])
+                        }
+                        _ => ("\"".to_owned(), value),
+                    };
+                    let value = value.replace("&amp;", "&");
+                    let rewritten = if key == "srcset" {
+                        srcset_candidates(&value)
+                            .into_iter()
+                            .map(|(src, descriptors)| {
+                                let src = self.rewrite_link(src, &base, dir, canonicalizer);
+                                match descriptors {
+                                    "" => src,
+                                    descriptors => format!("{src} {descriptors}"),
+                                }
+                            })
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    } else {
+                        self.rewrite_link(&value, &base, dir, canonicalizer)
+                    };
+                    if rewritten == value {
+                        return attribute[0].to_owned();
+                    }
+                    let escaped_quote = if quote == "'" { "%27" } else { "%22" };
+                    let rewritten = rewritten
+                        .replace('&', "&amp;")
+                        .replace(&quote, escaped_quote);
+                    format!("{name}{quote}{rewritten}{quote}")
+                })
+                .into_owned();
+            tag + rest
+        })
+        .into_owned()
+    }
+
+    fn rewrite_link(
+        &self,
+        link: &str,
+        base: &Url,
+        dir: &Path,
+        canonicalizer: &Canonicalizer,
+    ) -> String {
+        let link = link.trim();
+        if link.is_empty() || link.starts_with('#') {
+            return link.to_owned();
+        }
+        let is_relative = Url::parse(link).is_err();
+        if is_relative && self.mirror {
+            let file = link.split(['#', '?']).next().unwrap_or_default();
+            let file = percent_decode(file);
+            if !file.starts_with('/') && self.files.contains(&normalize(&dir.join(file))) {
+                return link.to_owned();
+            }
+        }
+        let mut url = match base.join(link) {
+            Ok(url) => url,
+            Err(_) => return link.to_owned(),
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return link.to_owned();
+        }
+        let fragment = url.fragment().map(str::to_owned);
+        url.set_fragment(None);
+        match self.by_url.get(&canonicalizer.canonicalize(url.clone())) {
+            Some(path) => {
+                let mut relative = percent_encode(&relative_path(dir, path));
+                if let Some(fragment) = fragment {
+                    relative = format!("{relative}#{fragment}");
+                }
+                relative
+            }
+            None => {
+                url.set_fragment(fragment.as_deref());
+                url.to_string()
+            }
+        }
+    }
+}
+
+/// `path` without `.` and with `..` applied.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The path to `to` from the directory `from`.
+pub fn relative_path(from: &Path, to: &Path) -> String {
+    let (from, to) = (normalize(from), normalize(to));
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut parts = vec![".."; from.len() - common];
+    let rest: Vec<_> = to[common..]
+        .iter()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    parts.extend(rest.iter().map(|part| part.as_ref()));
+    parts.join("/")
+}
+
+/// Escape the characters in a file path that a browser would read
+/// as part of a URL rather than of the file name.
+fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '%' | ' ' | '#' | '?' | '"' | '\'' => encoded += &format!("%{:02X}", c as u32),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = bytes
+            .get(index + 1..index + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -1,8 +1,9 @@
 use log::info;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::mem::take;
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Ring {
     pub max: u8,
     pub current: u8,
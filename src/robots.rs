@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use anyhow::Error;
+use futures::{future::BoxFuture, FutureExt};
+use log::{info, warn};
+use reqwest::{RequestBuilder, Url};
+use tokio::{spawn, task::JoinError, time::sleep};
+
+use crate::retry::{RequestError, RetryPolicy, StatusError};
+
+pub const DEFAULT_USER_AGENT: &str = "recursive_scraper";
+
+/// The name `user_agent` goes by in robots.txt and `X-Robots-Tag`,
+/// up to the first `/` or space,
+/// such as `recursive_scraper` for `recursive_scraper/0.6 (+https://…)`.
+pub fn product_token(user_agent: &str) -> &str {
+    user_agent
+        .split(|c: char| c == '/' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+}
+
+/// The rules in a robots.txt that apply to one user-agent token.
+#[derive(Clone, Debug, Default)]
+pub struct Robots {
+    /// `(allow, pattern)` for each `Allow` and `Disallow` line.
+    pub rules: Vec<(bool, String)>,
+    pub crawl_delay: Option<Duration>,
+    /// `Sitemap` lines, which apply regardless of the user-agent.
+    pub sitemaps: Vec<Url>,
+}
+
+impl Robots {
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    pub fn disallow_all() -> Self {
+        Self {
+            rules: vec![(false, "/".to_owned())],
+            ..Default::default()
+        }
+    }
+
+    /// Parse `text` and keep the groups that apply to
+    /// the [`product_token`] of `user_agent`,
+    /// or the `*` groups if none applies specifically.
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let user_agent = product_token(user_agent).to_lowercase();
+        let mut specific = Self::default();
+        let mut any = Self::default();
+        let mut matched_specific = false;
+        let mut sitemaps = Vec::new();
+        // User-agents of the current group, and whether its rules started.
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(value.to_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let rule = (key == "allow", value.to_owned());
+                    if agents.contains(&user_agent) {
+                        matched_specific = true;
+                        specific.rules.push(rule.clone());
+                    }
+                    if agents.iter().any(|agent| agent == "*") {
+                        any.rules.push(rule);
+                    }
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    let delay = match value.parse::<f64>().map(Duration::try_from_secs_f64) {
+                        Ok(Ok(delay)) => delay,
+                        _ => continue,
+                    };
+                    if agents.contains(&user_agent) {
+                        matched_specific = true;
+                        specific.crawl_delay = Some(delay);
+                    }
+                    if agents.iter().any(|agent| agent == "*") {
+                        any.crawl_delay = Some(delay);
+                    }
+                }
+                "sitemap" => match Url::parse(value) {
+                    Ok(url) => sitemaps.push(url),
+                    Err(err) => warn!("Sitemap `{value}`: {err}."),
+                },
+                _ => {}
+            }
+        }
+        let mut robots = if matched_specific { specific } else { any };
+        robots.sitemaps = sitemaps;
+        robots
+    }
+
+    /// The longest matching rule decides; `Allow` wins ties.
+    pub fn is_allowed(&self, url: &Url) -> bool {
+        let mut path = url.path().to_owned();
+        if let Some(query) = url.query() {
+            path.push('?');
+            path.push_str(query);
+        }
+        let mut decision = (0, true);
+        for (allow, pattern) in &self.rules {
+            if pattern_matches(pattern, &path) {
+                let len = pattern.len();
+                if len > decision.0 || (len == decision.0 && *allow) {
+                    decision = (len, *allow);
+                }
+            }
+        }
+        decision.1
+    }
+}
+
+/// Match `path` against a robots.txt path `pattern`,
+/// where `*` matches any sequence and a trailing `$` anchors the end.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(pattern) => (pattern, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap();
+    let mut rest = match path.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<_> = parts.collect();
+    for (index, part) in parts.iter().enumerate() {
+        let last = index == parts.len() - 1;
+        if last && anchored {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(position) => rest = &rest[position + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+/// Whether robots.txt applies to the URL, and if so, its origin.
+pub fn robots_origin(url: &Url) -> Option<String> {
+    match url.scheme() {
+        "http" | "https" => Some(url.origin().ascii_serialization()),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub enum RobotsEntry {
+    /// robots.txt is being fetched; the URL ids wait for it.
+    Fetching(Vec<usize>),
+    Fetched(Robots),
+}
+
+/// The origin, even if the task fetching its robots.txt panicked.
+pub type RobotsRequest = BoxFuture<'static, (String, Result<Robots, JoinError>)>;
+
+/// Fetch and parse robots.txt for `origin`.
+///
+/// A client error means there are no restrictions.
+/// A server error is retried according to `retry`,
+/// and means the whole origin is disallowed if it persists.
+/// A failed request allows everything so that the pages fail on their own.
+pub async fn spawn_robots_request(
+    origin: String,
+    request: RequestBuilder,
+    user_agent: String,
+    retry: RetryPolicy,
+) -> RobotsRequest {
+    let task = spawn(fetch_robots(origin.clone(), request, user_agent, retry));
+    async move { (origin, task.await) }.boxed()
+}
+
+async fn fetch_robots(
+    origin: String,
+    request: RequestBuilder,
+    user_agent: String,
+    retry: RetryPolicy,
+) -> Robots {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let request = request.try_clone().expect("GET requests have no body");
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(err) => {
+                warn!("{origin}/robots.txt: {err}.");
+                return Robots::allow_all();
+            }
+        };
+        let status = response.status();
+        if status.is_success() {
+            return match response.text().await {
+                Ok(text) => Robots::parse(&text, &user_agent),
+                Err(err) => {
+                    warn!("{origin}/robots.txt: {err}.");
+                    Robots::allow_all()
+                }
+            };
+        }
+        if !status.is_server_error() {
+            return Robots::allow_all();
+        }
+        let error = Error::from(StatusError::new(status, response.headers()));
+        match retry.backoff(attempts, &RequestError::from(&error)) {
+            Some(backoff) => {
+                info!("{origin}/robots.txt: {error}, retrying in {backoff:?}.");
+                sleep(backoff).await;
+            }
+            None => {
+                info!("{origin}/robots.txt: {error}, disallowing all.");
+                return Robots::disallow_all();
+            }
+        }
+    }
+}
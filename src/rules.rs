@@ -0,0 +1,168 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Error, Result};
+use clap::ValueEnum;
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::mime::glob_match;
+
+/// What happens to a URL that a rule matches.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Follow the URL, as if it matched `filter`.
+    Include,
+    /// Only record the URL, as if it matched `blacklist`.
+    Exclude,
+}
+
+/// What part of a URL a rule matches, and how.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// Regex searched in the whole URL.
+    Regex(Regex),
+    /// Glob matched against the whole URL, case-insensitively,
+    /// where `*` matches any characters.
+    Glob(String),
+    /// The host or any of its subdomains.
+    Host(String),
+    /// Paths starting with this.
+    Path(String),
+}
+
+impl Matcher {
+    pub fn is_match(&self, url: &Url) -> bool {
+        match self {
+            Self::Regex(regex) => regex.is_match(url.as_str()),
+            Self::Glob(glob) => glob_match(glob, url.as_str()),
+            Self::Host(host) => url.host_str().is_some_and(|url_host| {
+                url_host.eq_ignore_ascii_case(host)
+                    || url_host
+                        .to_ascii_lowercase()
+                        .ends_with(&format!(".{}", host.to_ascii_lowercase()))
+            }),
+            Self::Path(prefix) => url.path().starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// An include or exclude rule, written as `ACTION:KIND:PATTERN`,
+/// such as `exclude:glob:*.pdf` or `include:host:example.com`.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    pub matcher: Matcher,
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(rule: &str) -> Result<Self> {
+        let mut parts = rule.splitn(3, ':');
+        let (Some(action), Some(kind), Some(pattern)) = (parts.next(), parts.next(), parts.next())
+        else {
+            bail!("Rule `{rule}` is not `ACTION:KIND:PATTERN`.");
+        };
+        let action = match action {
+            "include" => Action::Include,
+            "exclude" => Action::Exclude,
+            _ => bail!("Rule `{rule}` does not start with `include` or `exclude`."),
+        };
+        let matcher = match kind {
+            "regex" => Matcher::Regex(Regex::new(pattern)?),
+            "glob" => Matcher::Glob(pattern.to_owned()),
+            "host" => Matcher::Host(pattern.to_owned()),
+            "path" => Matcher::Path(pattern.to_owned()),
+            _ => bail!("Rule `{rule}` has no kind `regex`, `glob`, `host` or `path`."),
+        };
+        Ok(Self { action, matcher })
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::Include => "include",
+            Action::Exclude => "exclude",
+        };
+        let (kind, pattern) = match &self.matcher {
+            Matcher::Regex(regex) => ("regex", regex.as_str()),
+            Matcher::Glob(glob) => ("glob", glob.as_str()),
+            Matcher::Host(host) => ("host", host.as_str()),
+            Matcher::Path(prefix) => ("path", prefix.as_str()),
+        };
+        write!(f, "{action}:{kind}:{pattern}")
+    }
+}
+
+/// Which of the rules matching a URL decides its fate.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleOrder {
+    /// The first matching rule decides.
+    #[default]
+    FirstMatch,
+    /// Later rules override earlier ones.
+    LastMatch,
+}
+
+/// The index and the rule that decides the fate of `url`, if any matches.
+pub fn matching_rule<'a>(
+    rules: &'a [Rule],
+    order: RuleOrder,
+    url: &Url,
+) -> Option<(usize, &'a Rule)> {
+    let mut matching = rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| rule.matcher.is_match(url));
+    match order {
+        RuleOrder::FirstMatch => matching.next(),
+        RuleOrder::LastMatch => matching.next_back(),
+    }
+}
+
+/// What is done with a discovered URL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fate {
+    /// Added to the pending list.
+    Follow,
+    /// Recorded but not requested.
+    Exclude,
+    /// Added to the next ring, if there are rings.
+    Outside,
+}
+
+/// What decided the fate of a URL.
+#[derive(Clone, Copy, Debug)]
+pub enum Reason<'a> {
+    /// The 0-based index of the rule, and the rule.
+    Rule(usize, &'a Rule),
+    Blacklist,
+    Filter,
+    /// Neither a rule, `blacklist` nor `filter` matches.
+    NoMatch,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decision<'a> {
+    pub fate: Fate,
+    pub reason: Reason<'a>,
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.fate {
+            Fate::Follow => "followed",
+            Fate::Exclude => "excluded",
+            Fate::Outside => "outside the filter",
+        })?;
+        match self.reason {
+            Reason::Rule(index, rule) => write!(f, " by rule {} `{rule}`", index + 1),
+            Reason::Blacklist => f.write_str(" by the blacklist"),
+            Reason::Filter => f.write_str(" by the filter"),
+            Reason::NoMatch => f.write_str(", matching no rule"),
+        }
+    }
+}
@@ -1,25 +1,57 @@
-use anyhow::Result;
-use bytes::Bytes;
+use anyhow::{Error, Result};
+use encoding_rs::{Encoding, UTF_8};
 use futures::StreamExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use reqwest::{Client, Response, Url};
-use std::{collections::BTreeSet, time::Duration};
-use tokio::time::{sleep, timeout, Instant};
+use reqwest::{
+    header::{HeaderMap, CONTENT_TYPE, USER_AGENT},
+    Client, Response, StatusCode, Url,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{copy, read, read_to_string},
+    time::{sleep, timeout, Instant},
+};
 
 use crate::{
-    config::SchedulerConfig,
-    file::FileContent,
-    io::{save_file, Writer},
-    middle::{spawn_process, spawn_request, Conclusion},
-    state::SchedulerState,
+    budget::StopReason,
+    charset::{decode_html, encode_html},
+    config::{Output, SchedulerConfig},
+    digest::{from_hex, to_hex, Digest},
+    file::{Body, FileContent, Link, Page},
+    hosts::host_of,
+    io::{move_file, save_file, save_file_atomic, Writer},
+    metadata::{rfc3339, MetadataTable},
+    middle::{spawn_process, spawn_request, Conclusion, Exchange, ProcessConfig, TooLarge},
+    mime::essence,
+    mirror::{make_room, mirror_path},
+    recrawl::{PreviousCrawl, CHANGES_DIR, PREVIOUS_METADATA_DIR, PREVIOUS_RECORD_DIR},
+    retry::RequestError,
+    rewrite::LocalPaths,
+    robots::{robots_origin, spawn_robots_request, Robots, RobotsEntry},
+    rules::{matching_rule, Action, Decision, Fate, Reason},
+    sitemap::{spawn_sitemap_request, Sitemap},
+    state::{Queue, SchedulerState},
     urls::Record,
+    warc::{exchange_records, WarcWriter},
 };
 
 pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 pub const TIMEOUT_MULTIPLIER: u32 = 5;
 pub const WRITE_FREQUENCY: usize = 8;
 pub const RECORD_DIR: &str = "record.toml";
+pub const QUEUE_DIR: &str = "queue.toml";
+pub const METADATA_DIR: &str = "metadata.toml";
+/// Directory in the log directory that non-HTML bodies are streamed into
+/// before being moved to where they are saved.
+pub const DOWNLOAD_DIR: &str = "downloads";
 
 pub fn client_with_timeout(timeout: Duration) -> Client {
     Client::builder()
@@ -38,6 +70,7 @@ pub struct Scheduler {
     cfg: SchedulerConfig,
     client: Client,
     rec: Record,
+    meta: MetadataTable,
     s: SchedulerState,
 }
 
@@ -53,45 +86,367 @@ impl Scheduler {
             cfg,
             client,
             rec: Record::default(),
+            meta: MetadataTable::default(),
             s: SchedulerState::default(),
         }
     }
 
     pub fn new(cfg: SchedulerConfig) -> Self {
-        Self::from_client(default_client(), cfg)
+        Self::from_client(client_with_timeout(cfg.connection_timeout), cfg)
+    }
+
+    /// A handle that stops the scheduler when set to `true`.
+    /// The scheduler then drains whatever is already started
+    /// via [`Self::finish`] instead of initiating more requests.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.s.stop.clone()
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.s.stop.load(Ordering::Relaxed)
     }
 
     pub fn delaying_requests(&self) -> bool {
-        self.s.time.elapsed() < self.cfg.delay
+        self.s.time.elapsed() < self.cfg.global_delay
+    }
+
+    /// Record the canonical form of `url`, see [`Record::check_add_url`].
+    pub fn check_add_url(&mut self, url: Url) -> Result<usize, usize> {
+        self.rec
+            .check_add_url(self.cfg.canonicalizer.canonicalize(url))
     }
 
     pub fn add_pending(&mut self, url: Url) {
-        if let Ok(index) = self.rec.check_add_url(url) {
-            self.s.pending.push_back(index);
+        if let Ok(index) = self.check_add_url(url) {
+            self.push_pending(index);
         }
     }
 
-    pub fn add_next_pending(&mut self, url: Url) {
-        if let Some(ref mut ring) = self.cfg.ring {
-            if let Ok(index) = self.rec.check_add_url(url) {
-                ring.next.push_back(index);
+    fn push_pending(&mut self, url_id: usize) {
+        let host = host_of(&self.rec.url_ids[&url_id]);
+        self.s.pending.push_back(host, url_id);
+    }
+
+    /// The delay between requests to the host of `url`.
+    pub fn host_delay(&self, url: &Url) -> Duration {
+        let host = url.host_str().unwrap_or_default();
+        if let Some((_, delay)) = self
+            .cfg
+            .host_delays
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(host))
+        {
+            return *delay;
+        }
+        if let Some(RobotsEntry::Fetched(Robots {
+            crawl_delay: Some(delay),
+            ..
+        })) = robots_origin(url).and_then(|origin| self.s.robots.get(&origin))
+        {
+            return *delay;
+        }
+        self.cfg.delay
+    }
+
+    /// Record `url` as discovered on the page `parent`, if it is new.
+    fn check_add_child(&mut self, url: Url, parent: Option<usize>) -> Result<usize, usize> {
+        let result = self.check_add_url(url);
+        if let (Ok(url_id), Some(parent)) = (result, parent) {
+            self.rec.set_parent(url_id, parent);
+        }
+        result
+    }
+
+    /// The fate of the canonical `url` and what decided it:
+    /// the matching rule, or else `blacklist`, or else `filter`.
+    pub fn decide(&self, url: &Url) -> Decision<'_> {
+        let (fate, reason) =
+            if let Some((index, rule)) = matching_rule(&self.cfg.rules, self.cfg.rule_order, url) {
+                let fate = match rule.action {
+                    Action::Include => Fate::Follow,
+                    Action::Exclude => Fate::Exclude,
+                };
+                (fate, Reason::Rule(index, rule))
+            } else if self.cfg.blacklist.is_match(url.as_str()) {
+                (Fate::Exclude, Reason::Blacklist)
+            } else if self.cfg.filter.is_match(url.as_str()) {
+                (Fate::Follow, Reason::Filter)
+            } else {
+                (Fate::Outside, Reason::NoMatch)
+            };
+        Decision { fate, reason }
+    }
+
+    /// The canonical form of `url` and its fate, for `--explain-url`.
+    pub fn explain_url(&self, url: Url) -> (Url, Decision<'_>) {
+        let url = self.cfg.canonicalizer.canonicalize(url);
+        let decision = self.decide(&url);
+        (url, decision)
+    }
+
+    /// Add a discovered URL to the pending list if it is followed,
+    /// to the next ring if it is outside the filter,
+    /// or only record it if it is excluded or beyond `max_depth`.
+    pub fn add_href(&mut self, href: Url, parent: Option<usize>) {
+        let href = self.cfg.canonicalizer.canonicalize(href);
+        let depth = parent.map_or(0, |parent| self.rec.depth(parent) + 1);
+        let too_deep = self.cfg.max_depth.is_some_and(|max| depth > max);
+        match self.decide(&href).fate {
+            Fate::Exclude => _ = self.check_add_child(href, parent),
+            _ if too_deep => _ = self.check_add_child(href, parent),
+            Fate::Follow => {
+                if let Ok(index) = self.check_add_child(href, parent) {
+                    self.push_pending(index);
+                }
+            }
+            Fate::Outside => self.add_next_pending(href, parent),
+        }
+    }
+
+    /// Add an asset found on the page `parent` to the pending list
+    /// if it matches `asset_filter` but not `asset_blacklist`,
+    /// or else only record it.
+    /// With `same_host_assets`, an asset on another host than `parent`
+    /// is not recorded, so that a page on its host can still request it.
+    pub fn add_asset(&mut self, url: Url, parent: usize) {
+        let url = self.cfg.canonicalizer.canonicalize(url);
+        if self.cfg.same_host_assets
+            && self
+                .rec
+                .url_ids
+                .get(&parent)
+                .is_some_and(|parent| host_of(parent) != host_of(&url))
+        {
+            debug!("{parent}: not requesting asset {url} on another host.");
+            return;
+        }
+        let allowed = self.cfg.asset_filter.is_match(url.as_str())
+            && !self.cfg.asset_blacklist.is_match(url.as_str());
+        match self.check_add_child(url, Some(parent)) {
+            Ok(index) if allowed => self.push_pending(index),
+            Ok(index) => debug!("{parent}: not requesting asset {index}."),
+            Err(_) => {}
+        }
+    }
+
+    pub fn add_next_pending(&mut self, url: Url, parent: Option<usize>) {
+        if self.cfg.ring.is_some() {
+            if let Ok(index) = self.check_add_child(url, parent) {
+                if let Some(ref mut ring) = self.cfg.ring {
+                    ring.next.push_back(index);
+                }
             }
         }
     }
 
+    /// Spawn a request for the first pending URL whose host is not delayed
+    /// and that robots.txt allows,
+    /// or for the robots.txt that needs to be checked first.
     pub async fn spawn_one_request(&mut self) -> bool {
-        let url_id = match self.s.pending.pop_front() {
-            Some(url_id) => url_id,
+        if self.check_budget() {
+            return false;
+        }
+        if self.spawn_sitemap_request().await {
+            return true;
+        }
+        loop {
+            let now = Instant::now();
+            let (host, url_id) = match self.s.pending.pop_ready(now) {
+                Some(r) => r,
+                None => return false,
+            };
+            if self.rec.scrapes.contains(&url_id) {
+                debug!("{url_id}: already scraped.");
+                continue;
+            }
+            if let Some(page) = self.rec.stand_ins.get(&url_id) {
+                debug!("{url_id}: {page} is saved in its place.");
+                continue;
+            }
+            if let Some(max) = self.cfg.budget.max_pages_per_host {
+                if self.s.host_requests.get(&host).is_some_and(|n| *n >= max) {
+                    info!("{url_id}: {host} has used up its budget of {max} pages.");
+                    self.rec.over_budget.insert(url_id);
+                    continue;
+                }
+            }
+            let url = self.rec.url_ids.get(&url_id).unwrap().to_owned();
+            let origin = match robots_origin(&url) {
+                Some(origin) if !self.cfg.ignore_robots => origin,
+                _ => {
+                    self.queue_sitemaps(&url, None);
+                    self.s.pending.delay_host(host, now + self.host_delay(&url));
+                    self.spawn_url_request(url_id, url).await;
+                    return true;
+                }
+            };
+            match self.s.robots.get_mut(&origin) {
+                None => {
+                    self.s.pending.delay_host(host, now + self.host_delay(&url));
+                    self.spawn_robots_request(origin, url_id, &url).await;
+                    return true;
+                }
+                Some(RobotsEntry::Fetching(waiting)) => waiting.push(url_id),
+                Some(RobotsEntry::Fetched(robots)) => {
+                    if robots.is_allowed(&url) {
+                        self.s.pending.delay_host(host, now + self.host_delay(&url));
+                        self.spawn_url_request(url_id, url).await;
+                        return true;
+                    }
+                    info!("{url_id}: disallowed by robots.txt.");
+                    self.rec.disallowed.insert(url_id);
+                }
+            }
+        }
+    }
+
+    async fn spawn_url_request(&mut self, url_id: usize, url: Url) {
+        info!("Requesting {url_id} | {url}.");
+        self.s.in_flight.insert(url_id);
+        *self.rec.attempts.entry(url_id).or_default() += 1;
+        self.s.requests_sent += 1;
+        *self.s.host_requests.entry(host_of(&url)).or_default() += 1;
+        let mut request = self
+            .client
+            .get(url)
+            .header(USER_AGENT, &self.cfg.user_agent);
+        if let Some(previous) = &self.s.previous {
+            for (name, value) in previous.conditional_headers(url_id) {
+                request = request.header(name, value);
+            }
+        }
+        self.s.requests.push(spawn_request(url_id, request).await);
+    }
+
+    async fn spawn_robots_request(&mut self, origin: String, url_id: usize, url: &Url) {
+        let robots_url = url.join("/robots.txt").unwrap();
+        info!("Requesting {robots_url} for {url_id}.");
+        self.s
+            .robots
+            .insert(origin.clone(), RobotsEntry::Fetching(vec![url_id]));
+        let request = self
+            .client
+            .get(robots_url)
+            .header(USER_AGENT, &self.cfg.user_agent);
+        let user_agent = self.cfg.user_agent.clone();
+        let retry = self.cfg.retry.clone();
+        self.s
+            .robots_requests
+            .push(spawn_robots_request(origin, request, user_agent, retry).await);
+    }
+
+    /// Put URLs waiting for robots.txt that has arrived
+    /// back to the front of the pending list.
+    pub async fn check_robots_requests(&mut self) {
+        while let Ok(Some((origin, result))) =
+            timeout(Duration::ZERO, self.s.robots_requests.next()).await
+        {
+            // Like a failed request, so that the waiting URLs go on.
+            let robots = match result {
+                Ok(robots) => robots,
+                Err(err) => {
+                    error!("{origin}/robots.txt: {err}.");
+                    Robots::allow_all()
+                }
+            };
+            debug!("{origin}/robots.txt: {robots:?}.");
+            if let Ok(origin_url) = Url::parse(&origin) {
+                self.queue_sitemaps(&origin_url, Some(&robots));
+            }
+            if let Some(RobotsEntry::Fetching(waiting)) =
+                self.s.robots.insert(origin, RobotsEntry::Fetched(robots))
+            {
+                for url_id in waiting.into_iter().rev() {
+                    let host = host_of(&self.rec.url_ids[&url_id]);
+                    self.s.pending.push_front(host, url_id);
+                }
+            }
+        }
+    }
+
+    /// Queue the sitemaps listed in `robots` and `/sitemap.xml`,
+    /// unless `robots` disallows it,
+    /// if the origin of `url` has not been looked at.
+    fn queue_sitemaps(&mut self, url: &Url, robots: Option<&Robots>) {
+        if !self.cfg.sitemaps {
+            return;
+        }
+        let origin = match robots_origin(url) {
+            Some(origin) => origin,
+            None => return,
+        };
+        if !self.s.sitemap_origins.insert(origin) {
+            return;
+        }
+        let listed = robots.map(|robots| robots.sitemaps.clone());
+        let default = url
+            .join("/sitemap.xml")
+            .ok()
+            .filter(|sitemap| robots.is_none_or(|robots| robots.is_allowed(sitemap)));
+        for sitemap in listed.into_iter().flatten().chain(default) {
+            self.queue_sitemap(sitemap);
+        }
+    }
+
+    fn queue_sitemap(&mut self, url: Url) {
+        if self.s.sitemaps_seen.insert(url.clone()) {
+            self.s.sitemaps.push_back(url);
+        }
+    }
+
+    /// Spawn a request for the first queued sitemap whose host is not delayed.
+    async fn spawn_sitemap_request(&mut self) -> bool {
+        let now = Instant::now();
+        let index = match self
+            .s
+            .sitemaps
+            .iter()
+            .position(|url| self.s.pending.is_ready(&host_of(url), now))
+        {
+            Some(index) => index,
             None => return false,
         };
-        let url = self.rec.url_ids.get(&url_id).unwrap().to_owned();
-        info!("Requesting {url_id} | {url}.");
+        let url = self.s.sitemaps.remove(index).unwrap();
+        self.s
+            .pending
+            .delay_host(host_of(&url), now + self.host_delay(&url));
+        info!("Requesting sitemap {url}.");
+        self.s.sitemaps_in_flight.insert(url.clone());
+        let request = self
+            .client
+            .get(url.clone())
+            .header(USER_AGENT, &self.cfg.user_agent);
         self.s
-            .requests
-            .push(spawn_request(url_id, self.client.get(url)).await);
+            .sitemap_requests
+            .push(spawn_sitemap_request(url, request).await);
         true
     }
 
+    /// Queue the sitemaps listed in sitemap indexes
+    /// and add the URLs listed in urlsets.
+    pub async fn check_sitemap_requests(&mut self) {
+        while let Ok(Some((url, sitemap))) =
+            timeout(Duration::ZERO, self.s.sitemap_requests.next()).await
+        {
+            self.s.sitemaps_in_flight.remove(&url);
+            match sitemap {
+                Ok(Sitemap::Index(sitemaps)) => {
+                    info!("Sitemap {url} lists {} sitemaps.", sitemaps.len());
+                    for sitemap in sitemaps {
+                        self.queue_sitemap(sitemap);
+                    }
+                }
+                Ok(Sitemap::UrlSet(urls)) => {
+                    info!("Sitemap {url} lists {} URLs.", urls.len());
+                    for url in urls {
+                        self.add_href(url, None);
+                    }
+                }
+                Err(err) => warn!("Sitemap {url}: {err}."),
+            }
+        }
+    }
+
     pub async fn check_requests(&mut self) {
         while self.check_one_request().await && self.delaying_requests() {}
     }
@@ -106,11 +461,15 @@ impl Scheduler {
             None => return false,
         };
         match result {
-            Ok((url_id, response_result)) => match response_result {
-                Ok(response) => self.process_response(url_id, response).await,
+            Ok((url_id, response_result, metadata)) => match response_result {
+                Ok(response) => {
+                    self.meta.0.insert(url_id, metadata);
+                    self.process_response(url_id, response).await
+                }
                 Err(err) => {
+                    self.meta.0.insert(url_id, metadata);
                     error!("{url_id}: {err}.");
-                    self.fail(url_id)
+                    self.fail(url_id, &err)
                 }
             },
             Err(err) => error!("Request: {}", err),
@@ -133,11 +492,25 @@ impl Scheduler {
         };
         match result {
             Ok((url_id, process_result)) => match process_result {
-                Ok(content) => self.s.conclusions.push_back(Conclusion { url_id, content }),
-                Err(err) => {
-                    error!("{url_id}: {err}.");
-                    self.fail(url_id)
-                }
+                Ok((content, exchange)) => self.s.conclusions.push_back(Conclusion {
+                    url_id,
+                    content,
+                    exchange,
+                }),
+                Err(err) => match err.downcast_ref::<TooLarge>() {
+                    Some(too_large) => {
+                        warn!("{url_id}: {too_large}, aborted.");
+                        if let Some(metadata) = self.meta.0.get_mut(&url_id) {
+                            metadata.error = Some(too_large.to_string());
+                        }
+                        self.s.in_flight.remove(&url_id);
+                        self.rec.oversized.insert(url_id);
+                    }
+                    None => {
+                        error!("{url_id}: {err}.");
+                        self.fail(url_id, &err)
+                    }
+                },
             },
             Err(err) => error!("Request: {}", err),
         }
@@ -145,14 +518,82 @@ impl Scheduler {
     }
 
     async fn process_response(&mut self, url_id: usize, response: Response) {
-        let final_url_id = match self.rec.check_final_url(url_id, &response).await {
+        self.s.in_flight.remove(&url_id);
+        let final_url = self
+            .cfg
+            .canonicalizer
+            .canonicalize(response.url().to_owned());
+        let final_url_id = match self.rec.check_final_url(url_id, final_url).await {
             Some(id) => id,
             None => return,
         };
+        self.s.in_flight.insert(final_url_id);
+        if final_url_id != url_id {
+            if let Some(metadata) = self.meta.0.get(&url_id).cloned() {
+                self.meta.0.insert(final_url_id, metadata);
+            }
+        }
+        if response.status() == StatusCode::NOT_MODIFIED && self.s.previous.is_some() {
+            self.not_modified(final_url_id);
+            return;
+        }
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if response.status().is_success() && !self.cfg.mime.downloads(content_type) {
+            info!("{final_url_id}: not downloading `{content_type}`.");
+            self.rec
+                .skipped_types
+                .insert(final_url_id, essence(content_type));
+            self.s.in_flight.remove(&final_url_id);
+            return;
+        }
         debug!("Processing {final_url_id}.");
-        self.s
-            .processes
-            .push(spawn_process(final_url_id, response).await);
+        let process = spawn_process(final_url_id, response, self.process_config());
+        self.s.processes.push(process.await);
+    }
+
+    /// Keep what the previous crawl saved for `url_id`, which is unchanged,
+    /// and follow the URLs first found on it then.
+    fn not_modified(&mut self, url_id: usize) {
+        info!("{url_id}: not modified.");
+        self.s.in_flight.remove(&url_id);
+        self.s.changes.unchanged.insert(url_id);
+        let previous = match self.s.previous.take() {
+            Some(previous) => previous,
+            None => return,
+        };
+        if let Some(path) = previous.record.paths.get(&url_id) {
+            self.rec.paths.insert(url_id, path.clone());
+        }
+        if let Some(old) = previous.metadata.0.get(&url_id) {
+            let metadata = self.meta.0.entry(url_id).or_default();
+            metadata.encoding.clone_from(&old.encoding);
+            metadata.digest.clone_from(&old.digest);
+            // 304 responses may leave out the validators.
+            metadata.etag = metadata.etag.take().or_else(|| old.etag.clone());
+            metadata.last_modified = metadata
+                .last_modified
+                .take()
+                .or_else(|| old.last_modified.clone());
+            if let Some(digest) = old.digest.as_deref().and_then(from_hex) {
+                self.rec.digests.entry(digest).or_insert(url_id);
+            }
+        }
+        for child in previous.children(url_id) {
+            let url = previous.record.url_ids[child].clone();
+            if previous.record.scrapes.contains(child) {
+                // Requested last time, possibly as an asset not filtered.
+                if let Ok(index) = self.check_add_child(url, Some(url_id)) {
+                    self.push_pending(index);
+                }
+            } else {
+                self.add_href(url, Some(url_id));
+            }
+        }
+        self.s.previous = Some(previous);
     }
 
     pub async fn process_conclusions(&mut self) {
@@ -160,79 +601,290 @@ impl Scheduler {
     }
 
     pub async fn process_one_conclusion(&mut self) -> bool {
-        let Conclusion { url_id, content } = match self.s.conclusions.pop_front() {
+        let Conclusion {
+            url_id,
+            content,
+            exchange,
+        } = match self.s.conclusions.pop_front() {
             Some(conclusion) => conclusion,
             None => return false, // No conclusions pending.
         };
-        match content {
-            FileContent::Html(text, hrefs, imgs) => {
-                self.process_html(url_id, text, hrefs, imgs).await
+        self.s.in_flight.remove(&url_id);
+        let size = match &content {
+            FileContent::Html(_) => exchange.body.len() as u64,
+            FileContent::Other(_, body) => body.size(),
+        };
+        self.s.bytes_downloaded += size;
+        let digest = content.digest();
+        let hex = to_hex(&digest);
+        if let Some(previous) = &self.s.previous {
+            self.s.changes.add(url_id, previous.digest(url_id), &hex);
+        }
+        if let Some(metadata) = self.meta.0.get_mut(&url_id) {
+            metadata.size = Some(size);
+            metadata.digest = Some(hex);
+        }
+        let result = match content {
+            FileContent::Html(page) => self.process_html(url_id, page, digest, exchange).await,
+            FileContent::Other(extension, body) => {
+                self.process_other(url_id, &extension, body, exchange).await
             }
-            FileContent::Other(extension, bytes) => {
-                self.process_other(url_id, &extension, bytes).await
+        };
+        match result {
+            Ok(()) => _ = self.rec.errors.remove(&url_id),
+            Err(err) => {
+                error!("{url_id}: {err}.");
+                self.fail(url_id, &err);
             }
         }
-        .unwrap_or_else(|err| {
-            error!("{url_id}: {err}.");
-            self.fail(url_id);
-        });
         true
     }
 
     async fn process_html(
         &mut self,
         url_id: usize,
-        text: String,
-        hrefs: BTreeSet<Url>,
-        imgs: BTreeSet<Url>,
+        page: Page,
+        digest: Digest,
+        exchange: Exchange,
     ) -> Result<()> {
-        for href in hrefs {
-            let href_str = href.as_str();
-            if !self.cfg.blacklist.is_match(href_str) {
-                if self.cfg.filter.is_match(href_str) {
-                    self.add_pending(href);
-                } else {
-                    self.add_next_pending(href);
+        let Page {
+            text,
+            links,
+            noindex,
+            nofollow,
+            canonical,
+            simhash,
+            encoding,
+        } = page;
+        let encoding = encoding.unwrap_or(UTF_8);
+        if let Some(metadata) = self.meta.0.get_mut(&url_id) {
+            metadata.encoding = Some(encoding.name().to_owned());
+        }
+        if let (true, Some(canonical)) = (self.cfg.canonicalizer.prefer_canonical, canonical) {
+            let canonical_id = match self.check_add_url(canonical) {
+                Ok(id) | Err(id) => id,
+            };
+            if canonical_id != url_id {
+                self.rec.canonicals.insert(url_id, canonical_id);
+                if self.rec.scrapes.contains(&canonical_id)
+                    || self.rec.stand_ins.contains_key(&canonical_id)
+                {
+                    info!("{url_id}: canonical {canonical_id} already scraped.");
+                    return Ok(());
                 }
-            } else {
-                _ = self.rec.check_add_url(href)
+                self.rec.stand_ins.insert(canonical_id, url_id);
             }
         }
-        if !self.cfg.disregard_other {
-            for img in imgs {
-                // Not filtering images.
-                self.add_pending(img);
+        if self.is_duplicate(url_id, digest, simhash) {
+            return Ok(());
+        }
+        let outlinks: Vec<_> = links.iter().map(|link| link.url.to_string()).collect();
+        let page_robots = self.cfg.page_robots;
+        for Link {
+            url,
+            kind,
+            nofollow: link_nofollow,
+        } in links
+        {
+            if self.cfg.follow.contains(&kind) {
+                if page_robots && (nofollow || link_nofollow) {
+                    debug!("{url_id}: not following {url} because of nofollow.");
+                    self.rec.nofollow.insert(url_id);
+                } else {
+                    self.add_href(url, Some(url_id));
+                }
+            } else if self.cfg.assets.contains(&kind) && !self.cfg.disregard_other {
+                self.add_asset(url, url_id);
             }
         }
-        if !self.cfg.disregard_html {
-            save_file(
-                &format!("{}/{url_id}.html", self.cfg.html_dir),
-                text.as_bytes(),
-            )
-            .await?;
+        if page_robots && noindex {
+            info!("{url_id}: not saving because of noindex.");
+            self.rec.noindex.insert(url_id);
+        } else if !self.cfg.disregard_html && self.check_save_type(url_id, &exchange.headers) {
+            match self.output_path(url_id, None) {
+                Some(path) if self.cfg.raw_html => self.save(url_id, path, &exchange.body).await?,
+                Some(path) => {
+                    let bytes = encode_html(&text, UTF_8, encoding != UTF_8);
+                    self.save(url_id, path, &bytes).await?
+                }
+                None => self.write_warc(url_id, &exchange, outlinks, None).await?,
+            }
         }
         Ok(())
     }
 
-    async fn process_other(&mut self, url_id: usize, extension: &str, bytes: Bytes) -> Result<()> {
-        if self.cfg.disregard_other {
+    async fn process_other(
+        &mut self,
+        url_id: usize,
+        extension: &str,
+        body: Body,
+        exchange: Exchange,
+    ) -> Result<()> {
+        if self.cfg.disregard_other || self.is_duplicate(url_id, body.digest(), None) {
+            body.discard().await;
             return Ok(());
         }
-        save_file(
-            &format!("{}/{url_id}{extension}", self.cfg.other_dir),
-            &bytes,
-        )
-        .await?;
+        match (self.output_path(url_id, Some(extension)), &body) {
+            (Some(path), Body::Memory(bytes)) => self.save(url_id, path, bytes).await?,
+            (Some(path), Body::File { path: temp, .. }) => {
+                let result = match self.make_room(path).await {
+                    Ok(path) => move_file(temp, &path).await.map(|_| path),
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(path) => _ = self.rec.paths.insert(url_id, path),
+                    Err(err) => {
+                        body.discard().await;
+                        return Err(err);
+                    }
+                }
+            }
+            (None, _) => {
+                let payload_file = match &body {
+                    Body::Memory(_) => None,
+                    Body::File { path, size, digest } => Some((path.clone(), *size, *digest)),
+                };
+                let result = self
+                    .write_warc(url_id, &exchange, Vec::new(), payload_file)
+                    .await;
+                body.discard().await;
+                result?
+            }
+        }
+        Ok(())
+    }
+
+    fn process_config(&self) -> ProcessConfig {
+        let download_dir = Some(PathBuf::from(&self.cfg.log_dir).join(DOWNLOAD_DIR));
+        ProcessConfig {
+            user_agent: self.cfg.user_agent.clone(),
+            mime: self.cfg.mime.clone(),
+            max_html_size: self.cfg.max_html_size,
+            max_body_size: self.cfg.max_body_size,
+            download_dir,
+        }
+    }
+
+    /// Whether the content type in `headers` is to be saved.
+    /// If not, record it as skipped for `url_id`.
+    fn check_save_type(&mut self, url_id: usize, headers: &HeaderMap) -> bool {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if self.cfg.mime.saves(content_type) {
+            return true;
+        }
+        info!("{url_id}: not saving `{content_type}`.");
+        self.rec.skipped_types.insert(url_id, essence(content_type));
+        false
+    }
+
+    /// Where to save `url_id`, or `None` for WARC output.
+    /// `extension` is `None` for HTML.
+    fn output_path(&self, url_id: usize, extension: Option<&str>) -> Option<String> {
+        match (self.cfg.output, extension) {
+            (Output::Directories, None) => Some(format!("{}/{url_id}.html", self.cfg.html_dir)),
+            (Output::Directories, Some(extension)) => {
+                Some(format!("{}/{url_id}{extension}", self.cfg.other_dir))
+            }
+            (Output::Mirror, extension) => {
+                let path = mirror_path(&self.rec.url_ids[&url_id], extension);
+                Some(format!("{}/{}", self.cfg.mirror_dir, path.display()))
+            }
+            (Output::Warc, _) => None,
+        }
+    }
+
+    /// Where to save a file meant for `path`,
+    /// moving saved files out of its way in mirror mode.
+    async fn make_room(&mut self, path: String) -> Result<String> {
+        if self.cfg.output != Output::Mirror {
+            return Ok(path);
+        }
+        let (path, moved) = make_room(Path::new(&self.cfg.mirror_dir), Path::new(&path)).await?;
+        for (from, to) in moved {
+            let from = from.to_string_lossy();
+            if let Some(saved) = self.rec.paths.values_mut().find(|saved| **saved == from) {
+                *saved = to.to_string_lossy().into_owned();
+            }
+        }
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    async fn save(&mut self, url_id: usize, path: String, bytes: &[u8]) -> Result<()> {
+        let path = self.make_room(path).await?;
+        save_file(&path, bytes).await?;
+        self.rec.rewritten.remove(&url_id);
+        self.rec.paths.insert(url_id, path);
         Ok(())
     }
 
-    /// Recursively scrape until there are no more pending URLs.
+    /// Append the request, response and metadata records of `url_id`
+    /// to the WARC file.
+    async fn write_warc(
+        &mut self,
+        url_id: usize,
+        exchange: &Exchange,
+        outlinks: Vec<String>,
+        payload_file: Option<(PathBuf, u64, Digest)>,
+    ) -> Result<()> {
+        let metadata = self.meta.0.get(&url_id);
+        let date = match metadata {
+            Some(metadata) => metadata.fetched_at.clone(),
+            None => rfc3339(SystemTime::now()),
+        };
+        let mut fields = vec![("scraperUrlId", url_id.to_string())];
+        if let Some(metadata) = metadata {
+            fields.push(("fetchTimeMs", metadata.response_time.to_string()));
+        }
+        fields.extend(outlinks.into_iter().map(|outlink| ("outlink", outlink)));
+        let mut records = exchange_records(exchange, &self.cfg.user_agent, date, &fields);
+        if let Some((path, size, digest)) = payload_file {
+            records[1].payload_file = Some((path, size));
+            records[1].payload_digest = Some(digest);
+        }
+        let warc = self
+            .s
+            .warc
+            .get_or_insert_with(|| WarcWriter::new(self.cfg.warc.clone()));
+        warc.write(&records).await
+    }
+
+    /// Why the scheduler stopped before running out of URLs, if it did.
+    pub fn stopped_by(&self) -> Option<StopReason> {
+        self.rec.stopped_by
+    }
+
+    /// Record `url_id` as a duplicate if its content is already recorded,
+    /// unless duplicates are kept.
+    fn is_duplicate(&mut self, url_id: usize, digest: Digest, simhash: Option<u64>) -> bool {
+        if self.cfg.keep_duplicates {
+            return false;
+        }
+        let max_distance = self.cfg.near_duplicate;
+        match self
+            .rec
+            .check_duplicate(url_id, digest, simhash, max_distance)
+        {
+            Some(original) => {
+                info!("{url_id}: duplicate of {original}, not saving.");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Recursively scrape until there are no more pending URLs
+    /// or the scheduler is stopped.
     pub async fn recursion(&mut self) {
         self.s.time = Instant::now();
+        self.s.start = self.s.time;
+        self.rec.stopped_by = None;
         let mut state_lens = self.s.lens();
         let mut record_lens = self.rec.lens();
         let mut changes: usize = 0;
-        while self.s.has_more_tasks() || self.increment_ring() {
+        while !self.check_budget() && (self.s.has_more_tasks() || self.increment_ring()) {
             self.one_cycle().await;
             if state_lens != self.s.lens() {
                 state_lens = self.s.lens();
@@ -244,19 +896,112 @@ impl Scheduler {
             if record_lens != self.rec.lens() {
                 changes += 1;
                 record_lens = self.rec.lens();
-                if changes % WRITE_FREQUENCY == 0 {
+                if changes.is_multiple_of(WRITE_FREQUENCY) {
                     self.write().await;
                 }
             }
         }
 
-        self.write_all().await;
+        if self.stopped() {
+            let stopped_by = *self.rec.stopped_by.get_or_insert(StopReason::Signal);
+            info!(
+                "Stopped by {stopped_by}, finishing {} requests in flight.",
+                self.s.in_flight.len()
+            );
+            self.finish().await;
+        } else {
+            self.write_all().await;
+        }
+        if self.s.previous.is_some() {
+            self.write_changes().await;
+        }
+        if self.cfg.rewrite_links && self.stopped_by() != Some(StopReason::Signal) {
+            self.rewrite_links().await;
+            // To remember the pages rewritten.
+            self.write_all().await;
+        }
+    }
+
+    /// Rewrite the links in the saved HTML pages
+    /// to the local paths of the pages and assets saved,
+    /// so that they can be browsed offline.
+    pub async fn rewrite_links(&mut self) {
+        let mirror = self.cfg.output == Output::Mirror;
+        let local = LocalPaths::from_record(&self.rec, mirror);
+        // Outside a mirror, links rewritten before cannot be told from
+        // links to other sites, so each page is rewritten only once.
+        let pages = self.rec.paths.iter().filter(|(url_id, path)| {
+            let path = path.to_ascii_lowercase();
+            (path.ends_with(".html") || path.ends_with(".htm"))
+                && (mirror || !self.rec.rewritten.contains(url_id))
+        });
+        let mut done = Vec::new();
+        for (url_id, path) in pages {
+            let url = &self.rec.url_ids[url_id];
+            let bytes = match read(path).await {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    error!("Rewriting {url_id} at {path}: {err}.");
+                    continue;
+                }
+            };
+            // Saved as UTF-8, or as received in the encoding recorded then,
+            // which may have come from `Content-Type` alone.
+            let recorded = self.meta.0.get(url_id).and_then(|metadata| {
+                let label = metadata.encoding.as_deref()?;
+                Encoding::for_label(label.as_bytes())
+            });
+            let (html, encoding) = match recorded.filter(|_| self.cfg.raw_html) {
+                Some(encoding) => {
+                    let (html, encoding, _) = encoding.decode(&bytes);
+                    (html.into_owned(), encoding)
+                }
+                None => decode_html(&bytes, None),
+            };
+            let rewritten =
+                local.rewrite_html(&html, url, Path::new(path), &self.cfg.canonicalizer);
+            if rewritten != html {
+                debug!("Rewriting links in {url_id} at {path}.");
+                let has_bom = Encoding::for_bom(&bytes).is_some();
+                let rewritten = encode_html(&rewritten, encoding, has_bom);
+                if let Err(err) = save_file_atomic(path, rewritten).await {
+                    error!("Rewriting {url_id} at {path}: {err}.");
+                    continue;
+                }
+            }
+            done.push(*url_id);
+        }
+        self.rec.rewritten.extend(done);
+    }
+
+    /// Whether the scheduler is stopped,
+    /// stopping it if a budget is used up.
+    fn check_budget(&mut self) -> bool {
+        if self.stopped() {
+            return true;
+        }
+        let exceeded = self.cfg.budget.exceeded(
+            self.s.requests_sent,
+            self.s.bytes_downloaded,
+            self.s.start.elapsed(),
+        );
+        match exceeded {
+            Some(stopped_by) => {
+                warn!("Used up the {stopped_by}.");
+                self.rec.stopped_by = Some(stopped_by);
+                self.s.stop.store(true, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
     }
 
     fn increment_ring(&mut self) -> bool {
         if let Some(ref mut ring) = self.cfg.ring {
             if let Some(pending) = ring.increment() {
-                self.s.pending = pending;
+                for url_id in pending {
+                    self.push_pending(url_id);
+                }
                 return true;
             }
         }
@@ -264,6 +1009,9 @@ impl Scheduler {
     }
 
     async fn one_cycle(&mut self) {
+        self.check_retries();
+        self.check_robots_requests().await;
+        self.check_sitemap_requests().await;
         self.check_spawn_request().await;
         self.check_requests().await;
         self.check_spawn_request().await;
@@ -271,12 +1019,19 @@ impl Scheduler {
         self.check_spawn_request().await;
         self.process_conclusions().await;
         self.check_spawn_request().await;
-        sleep(self.cfg.delay.saturating_sub(self.s.time.elapsed())).await;
+        sleep(self.cfg.global_delay.saturating_sub(self.s.time.elapsed())).await;
     }
 
     async fn check_spawn_request(&mut self) {
-        if !self.delaying_requests() && self.spawn_one_request().await {
-            self.s.time += self.cfg.delay;
+        if self.delaying_requests() {
+            return;
+        }
+        if self.spawn_one_request().await {
+            self.s.time += self.cfg.global_delay;
+        } else {
+            // Nothing to request yet, so wait for a whole `global_delay`
+            // instead of polling continuously.
+            self.s.time = Instant::now();
         }
     }
 
@@ -285,34 +1040,233 @@ impl Scheduler {
     pub async fn finish(&mut self) {
         self.s.time = Instant::now();
         while self.s.has_processing() {
+            self.check_robots_requests().await;
+            self.check_sitemap_requests().await;
             self.check_requests().await;
             self.check_processes().await;
             self.process_one_conclusion().await;
-            sleep(self.cfg.delay.saturating_sub(self.s.time.elapsed())).await;
-            self.s.time += self.cfg.delay;
+            sleep(self.cfg.global_delay.saturating_sub(self.s.time.elapsed())).await;
+            self.s.time += self.cfg.global_delay;
         }
         self.write_all().await;
     }
 
-    fn fail(&mut self, url_id: usize) {
-        if self.rec.fails.contains(&url_id) {
-            return;
+    /// Schedule a retry of `url_id` according to the retry policy,
+    /// or give up on it.
+    fn fail(&mut self, url_id: usize, err: &Error) {
+        if let Some(metadata) = self.meta.0.get_mut(&url_id) {
+            metadata.error = Some(err.to_string());
+        }
+        self.s.in_flight.remove(&url_id);
+        self.rec.scrapes.remove(&url_id);
+        let error = RequestError::from(err);
+        self.rec.errors.insert(url_id, error.kind);
+        let attempts = self.rec.attempts.get(&url_id).copied().unwrap_or_default();
+        match self.cfg.retry.backoff(attempts, &error) {
+            Some(backoff) => {
+                info!("{url_id}: retrying in {backoff:?} after {attempts} attempts.");
+                let now = Instant::now();
+                if let Some(until) = self
+                    .cfg
+                    .retry
+                    .retry_after(&error)
+                    .and_then(|retry_after| now.checked_add(retry_after))
+                {
+                    let host = host_of(&self.rec.url_ids[&url_id]);
+                    self.s.pending.delay_host(host, until);
+                }
+                let retry_at = now.checked_add(backoff).unwrap_or(now);
+                self.s.retries.insert((retry_at, url_id));
+            }
+            None => {
+                warn!(
+                    "{url_id}: giving up after {attempts} attempts on {:?} error.",
+                    error.kind
+                );
+                self.rec.fails.insert(url_id);
+            }
+        }
+    }
+
+    fn check_retries(&mut self) {
+        while let Some(url_id) = self.s.pop_retry(Instant::now()) {
+            self.push_pending(url_id);
+        }
+    }
+
+    /// Restore the record and the queue that a previous run
+    /// wrote to the log directory, so that the crawl continues
+    /// where it stopped.
+    ///
+    /// URLs that were in flight when the queue was written
+    /// are requested again.
+    pub async fn resume(&mut self) -> Result<()> {
+        let record = read_to_string(format!("{}/{RECORD_DIR}", self.cfg.log_dir)).await?;
+        self.rec = toml::from_str(&record)?;
+        let queue = read_to_string(format!("{}/{QUEUE_DIR}", self.cfg.log_dir)).await?;
+        let Queue {
+            in_flight,
+            pending,
+            ring,
+            sitemaps,
+            sitemaps_seen,
+            sitemap_origins,
+            recrawl,
+        } = toml::from_str(&queue)?;
+        // Logs written before the metadata table have none.
+        if let Ok(metadata) = read_to_string(format!("{}/{METADATA_DIR}", self.cfg.log_dir)).await {
+            self.meta = toml::from_str(&metadata)?;
+        }
+        if recrawl {
+            let previous = self.read_previous().await?;
+            self.rec.reserve_ids(&previous.record);
+            // The changes of the URLs downloaded before the interruption.
+            for (url_id, metadata) in &self.meta.0 {
+                if let Some(digest) = &metadata.digest {
+                    self.s
+                        .changes
+                        .add(*url_id, previous.digest(*url_id), digest);
+                }
+            }
+            self.s.previous = Some(previous);
+        }
+        for url_id in &in_flight {
+            self.rec.scrapes.remove(url_id);
+        }
+        // Skipped for the host budget of the previous run.
+        let over_budget = std::mem::take(&mut self.rec.over_budget);
+        for url_id in in_flight.into_iter().chain(pending).chain(over_budget) {
+            self.push_pending(url_id);
+        }
+        let parse = |urls: Vec<String>| urls.into_iter().filter_map(|url| Url::parse(&url).ok());
+        self.s.sitemaps = parse(sitemaps).collect();
+        self.s.sitemaps_seen = parse(sitemaps_seen).collect();
+        self.s.sitemap_origins = sitemap_origins.into_iter().collect();
+        if let (Some(ring), Some(saved)) = (&mut self.cfg.ring, ring) {
+            ring.current = saved.current.min(ring.max);
+            ring.next = saved.next;
+        }
+        info!(
+            "Resuming with {} URLs, {} scrapes, {} fails, {} pending.",
+            self.rec.urls.len(),
+            self.rec.scrapes.len(),
+            self.rec.fails.len(),
+            self.s.pending.len()
+        );
+        Ok(())
+    }
+
+    /// Start a new crawl over the record and metadata in the log directory,
+    /// requesting the URLs downloaded then conditionally,
+    /// and report the changes in the end.
+    ///
+    /// URLs keep their ids from the previous crawl.
+    pub async fn recrawl(&mut self) -> Result<()> {
+        // Kept for resuming this crawl, since it overwrites them.
+        // Without the digests, every URL would be reported as new.
+        for (from, to) in [
+            (RECORD_DIR, PREVIOUS_RECORD_DIR),
+            (METADATA_DIR, PREVIOUS_METADATA_DIR),
+        ] {
+            let (from, to) = (
+                format!("{}/{from}", self.cfg.log_dir),
+                format!("{}/{to}", self.cfg.log_dir),
+            );
+            if let Err(err) = copy(&from, &to).await {
+                return Err(Error::msg(format!("Re-crawling needs {from}: {err}.")));
+            }
+        }
+        let previous = self.read_previous().await?;
+        self.rec = Record::default();
+        self.rec.reserve_ids(&previous.record);
+        self.meta = MetadataTable::default();
+        info!(
+            "Re-crawling {} URLs, {} with validators.",
+            previous.record.urls.len(),
+            previous
+                .metadata
+                .0
+                .values()
+                .filter(|metadata| metadata.etag.is_some() || metadata.last_modified.is_some())
+                .count()
+        );
+        self.s.previous = Some(previous);
+        Ok(())
+    }
+
+    /// The crawl being re-crawled, as kept in the log directory by [`Self::recrawl`].
+    async fn read_previous(&self) -> Result<PreviousCrawl> {
+        let record = read_to_string(format!("{}/{PREVIOUS_RECORD_DIR}", self.cfg.log_dir)).await?;
+        let metadata =
+            read_to_string(format!("{}/{PREVIOUS_METADATA_DIR}", self.cfg.log_dir)).await?;
+        Ok(PreviousCrawl::new(
+            toml::from_str(&record)?,
+            toml::from_str(&metadata)?,
+        ))
+    }
+
+    async fn write_changes(&self) {
+        let previous = match &self.s.previous {
+            Some(previous) => previous,
+            None => return,
+        };
+        let report = self.s.changes.report(previous, &self.rec);
+        info!(
+            "{} new, {} changed, {} unchanged, {} vanished, {} unreached.",
+            report.new.len(),
+            report.changed.len(),
+            report.unchanged.len(),
+            report.vanished.len(),
+            report.unreached.len()
+        );
+        let path = format!("{}/{CHANGES_DIR}", self.cfg.log_dir);
+        if let Err(err) = save_file_atomic(&path, toml::to_string_pretty(&report).unwrap()).await {
+            error!("Writing {path}: {err}.");
+        }
+    }
+
+    fn queue(&self) -> Queue {
+        Queue {
+            in_flight: self.s.in_flight.iter().copied().collect(),
+            pending: self
+                .s
+                .waiting_for_robots()
+                .chain(self.s.pending.iter())
+                .chain(self.s.retries.iter().map(|(_, url_id)| *url_id))
+                .collect(),
+            ring: self.cfg.ring.clone(),
+            sitemaps: self
+                .s
+                .sitemaps_in_flight
+                .iter()
+                .chain(&self.s.sitemaps)
+                .map(Url::to_string)
+                .collect(),
+            sitemaps_seen: self.s.sitemaps_seen.iter().map(Url::to_string).collect(),
+            sitemap_origins: self.s.sitemap_origins.iter().cloned().collect(),
+            recrawl: self.s.previous.is_some(),
         }
-        self.rec.fails.insert(url_id);
-        self.s.pending.push_back(url_id);
     }
 
     async fn write(&mut self) {
         {
             let _ = self.s.writer.take();
         }
-        self.s.writer = Some(
-            Writer::spawn(
-                format!("{}/{RECORD_DIR}", self.cfg.log_dir),
-                toml::to_string_pretty(&self.rec).unwrap(),
-            )
-            .await,
-        );
+        let files = vec![
+            (
+                PathBuf::from(format!("{}/{RECORD_DIR}", self.cfg.log_dir)),
+                toml::to_string_pretty(&self.rec).unwrap().into_bytes(),
+            ),
+            (
+                PathBuf::from(format!("{}/{QUEUE_DIR}", self.cfg.log_dir)),
+                toml::to_string_pretty(&self.queue()).unwrap().into_bytes(),
+            ),
+            (
+                PathBuf::from(format!("{}/{METADATA_DIR}", self.cfg.log_dir)),
+                toml::to_string_pretty(&self.meta).unwrap().into_bytes(),
+            ),
+        ];
+        self.s.writer = Some(Writer::spawn_all(files).await);
     }
 
     async fn write_all(&mut self) {
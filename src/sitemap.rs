@@ -0,0 +1,68 @@
+use std::io::Read;
+
+use anyhow::{Error, Result};
+use flate2::read::GzDecoder;
+use futures::{future::BoxFuture, FutureExt};
+use log::warn;
+use reqwest::{RequestBuilder, Url};
+use select::{document::Document, predicate::Name};
+use tokio::spawn;
+
+use crate::middle::read_body;
+
+/// The most bytes a sitemap may have once decompressed, per sitemaps.org.
+pub const MAX_SITEMAP_SIZE: u64 = 50 * 1024 * 1024;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug)]
+pub enum Sitemap {
+    /// A sitemap index listing more sitemaps.
+    Index(Vec<Url>),
+    /// A urlset listing pages.
+    UrlSet(Vec<Url>),
+}
+
+/// Parse a sitemap index or urlset, gzipped or not.
+pub fn parse_sitemap(bytes: &[u8]) -> Result<Sitemap> {
+    let mut xml = Vec::new();
+    if bytes.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(bytes)
+            .take(MAX_SITEMAP_SIZE)
+            .read_to_end(&mut xml)?;
+    } else {
+        xml.extend_from_slice(bytes);
+    }
+    // The HTML parser is lenient enough for the few elements sitemaps use.
+    let document = Document::from(String::from_utf8_lossy(&xml).as_ref());
+    let mut urls = Vec::new();
+    for loc in document.find(Name("loc")) {
+        let loc = loc.text();
+        match Url::parse(loc.trim()) {
+            Ok(url) => urls.push(url),
+            Err(err) => warn!("Sitemap loc `{loc}`: {err}."),
+        }
+    }
+    Ok(if document.find(Name("sitemapindex")).next().is_some() {
+        Sitemap::Index(urls)
+    } else {
+        Sitemap::UrlSet(urls)
+    })
+}
+
+/// Resolves to the URL of the sitemap even if the task panics,
+/// so that it is no longer counted as in flight.
+pub type SitemapRequest = BoxFuture<'static, (Url, Result<Sitemap>)>;
+
+pub async fn spawn_sitemap_request(url: Url, request: RequestBuilder) -> SitemapRequest {
+    let task = spawn(fetch_sitemap(request));
+    async move { (url, task.await.unwrap_or_else(|err| Err(err.into()))) }.boxed()
+}
+
+async fn fetch_sitemap(request: RequestBuilder) -> Result<Sitemap> {
+    let mut response = request.send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Error::msg(format!("status code error: {status}")));
+    }
+    parse_sitemap(&read_body(&mut response, Some(MAX_SITEMAP_SIZE)).await?)
+}
@@ -1,43 +1,108 @@
-use std::collections::VecDeque;
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use futures::stream::FuturesUnordered;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
 use crate::{
+    hosts::HostQueues,
     io::Writer,
     middle::{Conclusion, Process, Request},
+    recrawl::{Changes, PreviousCrawl},
+    ring::Ring,
+    robots::{RobotsEntry, RobotsRequest},
+    sitemap::SitemapRequest,
+    warc::WarcWriter,
 };
 
 #[derive(Debug)]
 pub struct SchedulerState {
     pub time: Instant,
-    pub pending: VecDeque<usize>,
+    /// When the crawl started, for the time budget.
+    pub start: Instant,
+    /// Number of pages and assets requested in this run.
+    pub requests_sent: usize,
+    /// Total size of the bodies downloaded in this run.
+    pub bytes_downloaded: u64,
+    /// Number of pages and assets requested from each host in this run.
+    pub host_requests: BTreeMap<String, usize>,
+    pub pending: HostQueues,
+    /// URL ids that have been requested but not yet concluded.
+    pub in_flight: BTreeSet<usize>,
+    /// Failed URL ids and when to request them again.
+    pub retries: BTreeSet<(Instant, usize)>,
     pub requests: FuturesUnordered<Request>,
     pub processes: FuturesUnordered<Process>,
     pub conclusions: VecDeque<Conclusion>,
+    /// robots.txt of each origin, by `Origin::ascii_serialization`.
+    pub robots: BTreeMap<String, RobotsEntry>,
+    pub robots_requests: FuturesUnordered<RobotsRequest>,
+    /// Origins whose sitemaps have been looked for.
+    pub sitemap_origins: BTreeSet<String>,
+    /// Sitemaps ever queued, to not fetch them twice.
+    pub sitemaps_seen: BTreeSet<Url>,
+    pub sitemaps: VecDeque<Url>,
+    /// Sitemaps requested but not yet parsed.
+    pub sitemaps_in_flight: BTreeSet<Url>,
+    pub sitemap_requests: FuturesUnordered<SitemapRequest>,
     pub writer: Option<Writer>,
+    /// Started on the first page or asset saved as WARC.
+    pub warc: Option<WarcWriter>,
+    /// The crawl being re-crawled, if any.
+    pub previous: Option<PreviousCrawl>,
+    pub changes: Changes,
+    /// Set to stop spawning new requests and drain the scheduler.
+    pub stop: Arc<AtomicBool>,
 }
 
 impl Default for SchedulerState {
     fn default() -> Self {
         Self {
             time: Instant::now(),
-            pending: VecDeque::new(),
+            start: Instant::now(),
+            requests_sent: 0,
+            bytes_downloaded: 0,
+            host_requests: BTreeMap::new(),
+            pending: HostQueues::default(),
+            in_flight: BTreeSet::new(),
+            retries: BTreeSet::new(),
             requests: FuturesUnordered::new(),
             processes: FuturesUnordered::new(),
             conclusions: VecDeque::new(),
+            robots: BTreeMap::new(),
+            robots_requests: FuturesUnordered::new(),
+            sitemap_origins: BTreeSet::new(),
+            sitemaps_seen: BTreeSet::new(),
+            sitemaps: VecDeque::new(),
+            sitemaps_in_flight: BTreeSet::new(),
+            sitemap_requests: FuturesUnordered::new(),
             writer: None,
+            warc: None,
+            previous: None,
+            changes: Changes::default(),
+            stop: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
 impl SchedulerState {
     pub fn has_processing(&self) -> bool {
-        !self.requests.is_empty() || !self.processes.is_empty() || !self.conclusions.is_empty()
+        !self.requests.is_empty()
+            || !self.processes.is_empty()
+            || !self.conclusions.is_empty()
+            || !self.robots_requests.is_empty()
+            || !self.sitemap_requests.is_empty()
     }
 
     pub fn has_more_tasks(&self) -> bool {
-        !self.pending.is_empty() || self.has_processing()
+        !self.pending.is_empty()
+            || !self.retries.is_empty()
+            || !self.sitemaps.is_empty()
+            || self.has_processing()
     }
 
     pub fn lens(&self) -> (usize, usize, usize, usize) {
@@ -48,4 +113,42 @@ impl SchedulerState {
             self.conclusions.len(),
         )
     }
+
+    /// Pop a URL id whose retry is due at `now`.
+    pub fn pop_retry(&mut self, now: Instant) -> Option<usize> {
+        match self.retries.first() {
+            Some((time, _)) if *time <= now => self.retries.pop_first().map(|(_, id)| id),
+            _ => None,
+        }
+    }
+
+    /// URL ids waiting for robots.txt of their origins.
+    pub fn waiting_for_robots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.robots
+            .values()
+            .flat_map(|entry| match entry {
+                RobotsEntry::Fetching(waiting) => waiting.as_slice(),
+                RobotsEntry::Fetched(_) => &[],
+            })
+            .copied()
+    }
+}
+
+/// The part of the scheduler state needed to resume a crawl,
+/// written next to the record.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Queue {
+    pub in_flight: Vec<usize>,
+    pub pending: Vec<usize>,
+    pub ring: Option<Ring>,
+    /// Sitemaps queued or in flight.
+    #[serde(default)]
+    pub sitemaps: Vec<String>,
+    #[serde(default)]
+    pub sitemaps_seen: Vec<String>,
+    #[serde(default)]
+    pub sitemap_origins: Vec<String>,
+    /// Whether this is a re-crawl of the crawl kept in the log directory.
+    #[serde(default)]
+    pub recrawl: bool,
 }
@@ -1,16 +1,44 @@
-use std::time::Duration;
+use std::{
+    collections::BTreeSet,
+    io::{Read, Write},
+    time::Duration,
+};
 
 use anyhow::{Ok, Result};
+use encoding_rs::{GBK, SHIFT_JIS, UTF_8, WINDOWS_1252};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
 use regex::Regex;
-use reqwest::{Client, Url};
-use tokio::time::sleep;
+use reqwest::{
+    header::{HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, RETRY_AFTER},
+    Client, StatusCode, Url,
+};
+use tokio::time::{sleep, Instant};
 
 use crate::{
-    config::SchedulerConfig,
-    io::save_file,
-    middle::spawn_request,
+    budget::{Budget, StopReason},
+    canonical::{Canonicalizer, TrailingSlash},
+    charset::{decode_html, encode_html, sniff_encoding},
+    config::{Output, SchedulerConfig},
+    config_file::ConfigFile,
+    digest::{fnv1a, from_hex, hamming_distance, sha1, simhash, to_hex, Sha1Hasher},
+    file::{links_from_html, srcset_candidates, Body, LinkKind},
+    hosts::HostQueues,
+    io::{move_file, save_file},
+    metadata::{rfc3339, Metadata, MetadataTable},
+    middle::{spawn_request, Exchange},
+    mime::{extension, glob_match, MimePolicy},
+    mirror::{make_room, mirror_path},
+    recrawl::{ChangeReport, Changes, PreviousCrawl},
+    retry::{parse_retry_after, ErrorKind, RequestError, RetryPolicy, StatusError},
+    rewrite::LocalPaths,
+    ring::Ring,
+    robots::{spawn_robots_request, Robots},
+    rules::{Fate, Reason, Rule, RuleOrder},
     schedule::{default_client, Scheduler},
+    sitemap::{parse_sitemap, Sitemap},
+    state::Queue,
     urls::Record,
+    warc::{exchange_records, WarcConfig, WarcWriter},
 };
 
 #[tokio::test]
@@ -52,7 +80,8 @@ async fn request_test() -> Result<()> {
         println!("Request hasn't finished.");
         sleep(Duration::from_millis(250)).await;
     }
-    let (_, response_result) = request.await?;
+    let (_, response_result, metadata) = request.await?;
+    dbg!(metadata);
     let response = response_result?;
     dbg!(response);
     Ok(())
@@ -124,3 +153,1384 @@ fn record_serialize_test() -> Result<()> {
     println!("{toml}");
     Ok(())
 }
+
+#[test]
+fn record_deserialize_test() -> Result<()> {
+    let mut record = Record::default();
+    record
+        .check_add_url(Url::parse("https://www.rust-lang.org")?)
+        .unwrap();
+    record
+        .check_add_url(Url::parse("https://sites.duke.edu/intersections/")?)
+        .unwrap();
+    record.scrapes.insert(0);
+    record.fails.insert(1);
+    record.redirects.insert(1, 0);
+    record.canonicals.insert(0, 1);
+    record.stand_ins.insert(1, 0);
+    record.rewritten.insert(0);
+    let toml = toml::to_string_pretty(&record)?;
+    let deserialized: Record = toml::from_str(&toml)?;
+    assert_eq!(deserialized.urls, record.urls);
+    assert_eq!(deserialized.url_ids, record.url_ids);
+    assert_eq!(deserialized.scrapes, record.scrapes);
+    assert_eq!(deserialized.fails, record.fails);
+    assert_eq!(deserialized.redirects, record.redirects);
+    assert_eq!(deserialized.canonicals, record.canonicals);
+    assert_eq!(deserialized.stand_ins, record.stand_ins);
+    assert_eq!(deserialized.rewritten, record.rewritten);
+    assert_eq!(toml::to_string_pretty(&deserialized)?, toml);
+    Ok(())
+}
+
+#[test]
+fn queue_serialize_test() -> Result<()> {
+    let mut ring = Ring::new(2);
+    ring.next.push_back(3);
+    let queue = Queue {
+        in_flight: vec![1],
+        pending: vec![2, 4],
+        ring: Some(ring),
+        ..Queue::default()
+    };
+    let toml = toml::to_string_pretty(&queue)?;
+    println!("{toml}");
+    let deserialized: Queue = toml::from_str(&toml)?;
+    assert_eq!(deserialized.pending, queue.pending);
+    assert_eq!(deserialized.ring.unwrap().next, [3]);
+    // Written before sitemaps were saved.
+    let old: Queue = toml::from_str("in_flight = []\npending = [1]\n")?;
+    assert!(old.sitemaps.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sitemap_resume_test() -> Result<()> {
+    let dir = "dne/sitemap_resume";
+    let queue = Queue {
+        sitemaps: vec!["https://example.com/sitemap2.xml".into()],
+        sitemaps_seen: vec![
+            "https://example.com/sitemap.xml".into(),
+            "https://example.com/sitemap2.xml".into(),
+        ],
+        sitemap_origins: vec!["https://example.com".into()],
+        ..Queue::default()
+    };
+    save_file(format!("{dir}/queue.toml"), toml::to_string(&queue)?).await?;
+    save_file(
+        format!("{dir}/record.toml"),
+        toml::to_string(&Record::default())?,
+    )
+    .await?;
+    let mut scheduler = Scheduler::new(SchedulerConfig::default().log_dir(dir.into()));
+    scheduler.resume().await?;
+    scheduler.finish().await;
+    let resumed: Queue = toml::from_str(&std::fs::read_to_string(format!("{dir}/queue.toml"))?)?;
+    assert_eq!(resumed.sitemaps, queue.sitemaps);
+    assert_eq!(resumed.sitemaps_seen, queue.sitemaps_seen);
+    assert_eq!(resumed.sitemap_origins, queue.sitemap_origins);
+    Ok(())
+}
+
+#[tokio::test]
+async fn scheduler_stop_test() -> Result<()> {
+    let mut scheduler = Scheduler::new(SchedulerConfig::default().log_dir("dne/stop".into()));
+    scheduler.add_pending(Url::parse("https://www.rust-lang.org")?);
+    scheduler
+        .stop_handle()
+        .store(true, std::sync::atomic::Ordering::Relaxed);
+    scheduler.recursion().await;
+    assert!(scheduler.stopped());
+    let queue: Queue = toml::from_str(&std::fs::read_to_string("dne/stop/queue.toml")?)?;
+    assert_eq!(queue.pending, [0]);
+    Ok(())
+}
+
+#[test]
+fn robots_test() -> Result<()> {
+    let text = "\
+User-agent: *
+Disallow: /private/
+Allow: /private/public$
+
+User-agent: other
+User-agent: recursive_scraper
+Disallow: /*.pdf$
+Disallow: /search?
+Allow: /search?q=allowed # Longer, so it wins.
+";
+    let robots = Robots::parse(text, "recursive_scraper");
+    let allowed = |url: &str| robots.is_allowed(&Url::parse(url).unwrap());
+    assert!(allowed("https://example.com/private/"));
+    assert!(!allowed("https://example.com/a/b.pdf"));
+    assert!(allowed("https://example.com/a/b.pdf.html"));
+    assert!(!allowed("https://example.com/search?q=1"));
+    assert!(allowed("https://example.com/search?q=allowed"));
+
+    // Groups are matched by the product token of the user-agent.
+    let robots = Robots::parse(text, "Recursive_Scraper/0.6 (+https://example.com/bot)");
+    assert!(!robots.is_allowed(&Url::parse("https://example.com/a/b.pdf")?));
+    assert!(robots.is_allowed(&Url::parse("https://example.com/private/")?));
+
+    let robots = Robots::parse(text, "unknown");
+    let allowed = |url: &str| robots.is_allowed(&Url::parse(url).unwrap());
+    assert!(!allowed("https://example.com/private/a"));
+    assert!(allowed("https://example.com/private/public"));
+    assert!(!allowed("https://example.com/private/public/a"));
+    assert!(allowed("https://example.com/a/b.pdf"));
+    Ok(())
+}
+
+/// Serve each of `responses` to one connection on localhost.
+async fn serve(responses: Vec<String>) -> Result<std::net::SocketAddr> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let address = listener.local_addr()?;
+    tokio::spawn(async move {
+        for response in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            _ = stream.read(&mut request).await;
+            _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    Ok(address)
+}
+
+#[tokio::test]
+async fn robots_retry_test() -> Result<()> {
+    let response = |status: &str, body: &str| {
+        format!(
+            "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    };
+    let unavailable = response("503 Service Unavailable", "");
+    let retry = RetryPolicy {
+        max_attempts: 2,
+        delay: Duration::from_millis(10),
+        ..RetryPolicy::default()
+    };
+    let fetch = |address: std::net::SocketAddr| {
+        let client = Client::builder().no_proxy().build().unwrap();
+        let request = client.get(format!("http://{address}/robots.txt"));
+        spawn_robots_request("origin".into(), request, "x".into(), retry.clone())
+    };
+    let url = Url::parse("https://example.com/a")?;
+
+    // A transient server error is retried.
+    let ok = response("200 OK", "User-agent: *\nDisallow: /private/\n");
+    let address = serve(vec![unavailable.clone(), ok]).await?;
+    let (origin, robots) = fetch(address).await.await;
+    assert_eq!(origin, "origin");
+    assert!(robots?.is_allowed(&url));
+
+    // A lasting one disallows the origin.
+    let address = serve(vec![unavailable.clone(), unavailable]).await?;
+    let (_, robots) = fetch(address).await.await;
+    assert!(!robots?.is_allowed(&url));
+    Ok(())
+}
+
+#[test]
+fn host_queues_test() {
+    let mut queues = HostQueues::default();
+    queues.push_back("a.com".into(), 0);
+    queues.push_back("a.com".into(), 1);
+    queues.push_back("b.com".into(), 2);
+    assert_eq!(queues.len(), 3);
+    let now = Instant::now();
+    assert_eq!(queues.pop_ready(now), Some(("a.com".into(), 0)));
+    queues.delay_host("a.com".into(), now + Duration::from_secs(1));
+    assert_eq!(queues.pop_ready(now), Some(("b.com".into(), 2)));
+    assert_eq!(queues.pop_ready(now), None);
+    assert_eq!(
+        queues.pop_ready(now + Duration::from_secs(1)),
+        Some(("a.com".into(), 1))
+    );
+    assert!(queues.is_empty());
+}
+
+#[test]
+fn host_delay_test() -> Result<()> {
+    let robots = Robots::parse("User-agent: *\nCrawl-delay: 2.5\n", "recursive_scraper");
+    assert_eq!(robots.crawl_delay, Some(Duration::from_millis(2500)));
+    let scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .delay(Duration::from_millis(100))
+            .host_delay(Regex::new(r"\.example\.com$")?, Duration::from_secs(3)),
+    );
+    let delay = |url: &str| scheduler.host_delay(&Url::parse(url).unwrap());
+    assert_eq!(delay("https://www.example.com/"), Duration::from_secs(3));
+    assert_eq!(delay("https://example.org/"), Duration::from_millis(100));
+    Ok(())
+}
+
+#[test]
+fn sitemap_test() -> Result<()> {
+    let index = br#"<?xml version="1.0" encoding="UTF-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <sitemap><loc>https://example.com/sitemap1.xml.gz</loc></sitemap>
+</sitemapindex>"#;
+    match parse_sitemap(index)? {
+        Sitemap::Index(sitemaps) => assert_eq!(
+            sitemaps,
+            [Url::parse("https://example.com/sitemap1.xml.gz")?]
+        ),
+        sitemap => panic!("{sitemap:?}"),
+    }
+
+    let urlset = br#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+  <url><loc> https://example.com/a </loc><lastmod>2023-01-01</lastmod></url>
+  <url><loc>https://example.com/b?c=d&amp;e=f</loc></url>
+</urlset>"#;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(urlset)?;
+    match parse_sitemap(&encoder.finish()?)? {
+        Sitemap::UrlSet(urls) => assert_eq!(
+            urls,
+            [
+                Url::parse("https://example.com/a")?,
+                Url::parse("https://example.com/b?c=d&e=f")?
+            ]
+        ),
+        sitemap => panic!("{sitemap:?}"),
+    }
+
+    let robots = Robots::parse(
+        "Sitemap: https://example.com/sitemap.xml\nUser-agent: *\nDisallow:\n",
+        "recursive_scraper",
+    );
+    assert_eq!(
+        robots.sitemaps,
+        [Url::parse("https://example.com/sitemap.xml")?]
+    );
+    Ok(())
+}
+
+#[test]
+fn links_from_html_test() -> Result<()> {
+    let html = r#"<html><head>
+<meta http-equiv="Refresh" content="5; URL='/next'">
+<link rel="stylesheet" href="/style.css">
+<link rel="icon" href="/favicon.ico">
+<script src="app.js"></script>
+</head><body>
+<a href="/page#section">Page</a>
+<a href="mailto:someone@example.com">Mail</a>
+<map><area href="/area"></map>
+<form action="/search"></form>
+<iframe src="/frame"></iframe>
+<img src="/a.png" srcset="/a-1x.png 1x, /a-2x.png 2x">
+<picture><source srcset="/b.webp"><source srcset="/c,1.png 1x,/c,2.png 2x"></picture>
+<video src="/v.mp4" poster="/poster.jpg"><track src="/subs.vtt"></video>
+<object data="/doc.pdf"></object>
+</body></html>"#;
+    let url = Url::parse("https://example.com/dir/")?;
+    let page = links_from_html(&url, html.to_owned());
+    let found: Vec<_> = page
+        .links
+        .iter()
+        .map(|link| (link.url.as_str(), link.kind))
+        .collect();
+    for expected in [
+        ("https://example.com/next", LinkKind::Refresh),
+        ("https://example.com/style.css", LinkKind::Stylesheet),
+        ("https://example.com/favicon.ico", LinkKind::Link),
+        ("https://example.com/dir/app.js", LinkKind::Script),
+        ("https://example.com/page", LinkKind::Anchor),
+        ("https://example.com/area", LinkKind::Area),
+        ("https://example.com/search", LinkKind::Form),
+        ("https://example.com/frame", LinkKind::Frame),
+        ("https://example.com/a.png", LinkKind::Image),
+        ("https://example.com/a-2x.png", LinkKind::Image),
+        ("https://example.com/b.webp", LinkKind::Image),
+        ("https://example.com/c,1.png", LinkKind::Image),
+        ("https://example.com/c,2.png", LinkKind::Image),
+        ("https://example.com/v.mp4", LinkKind::Media),
+        ("https://example.com/poster.jpg", LinkKind::Image),
+        ("https://example.com/subs.vtt", LinkKind::Media),
+        ("https://example.com/doc.pdf", LinkKind::Object),
+    ] {
+        assert!(found.contains(&expected), "{expected:?} not in {found:#?}");
+    }
+    assert_eq!(found.len(), 18);
+
+    assert_eq!(
+        srcset_candidates("data:image/png;base64,AAAA 1x,b.png, c.png 100w , d.png (a, b) 2x,,"),
+        [
+            ("data:image/png;base64,AAAA", "1x"),
+            ("b.png", ""),
+            ("c.png", "100w"),
+            ("d.png", "(a, b) 2x"),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn base_href_test() -> Result<()> {
+    let html = r#"<html><head><base href="/base/dir/"></head><body>
+<a href="relative">Relative</a>
+<a href="../up">Up</a>
+<a href="//cdn.example.org/lib">Protocol-relative</a>
+<a href="?page=2">Query-only</a>
+<a href="/absolute">Absolute path</a>
+</body></html>"#;
+    let url = Url::parse("https://example.com/articles/post.html?page=1")?;
+    let page = links_from_html(&url, html.to_owned());
+    let found: BTreeSet<_> = page.links.iter().map(|link| link.url.as_str()).collect();
+    assert_eq!(
+        found,
+        BTreeSet::from([
+            "https://example.com/base/dir/relative",
+            "https://example.com/base/up",
+            "https://cdn.example.org/lib",
+            "https://example.com/base/dir/?page=2",
+            "https://example.com/absolute",
+        ])
+    );
+
+    let html = r#"<a href="?page=2">Query-only</a><a href="//cdn.example.org/lib">CDN</a>"#;
+    let url = Url::parse("http://example.com/articles/post.html?page=1")?;
+    let page = links_from_html(&url, html.to_owned());
+    let found: BTreeSet<_> = page.links.iter().map(|link| link.url.as_str()).collect();
+    assert_eq!(
+        found,
+        BTreeSet::from([
+            "http://example.com/articles/post.html?page=2",
+            "http://cdn.example.org/lib",
+        ])
+    );
+    Ok(())
+}
+
+#[test]
+fn page_robots_test() -> Result<()> {
+    let html = r#"<html><head><meta name="ROBOTS" content="noindex"></head><body>
+<a href="/followed">Followed</a>
+<a href="/sponsored" rel="sponsored nofollow">Sponsored</a>
+</body></html>"#;
+    let url = Url::parse("https://example.com/")?;
+    let mut page = links_from_html(&url, html.to_owned());
+    assert!(page.noindex);
+    assert!(!page.nofollow);
+    let nofollow: Vec<_> = page
+        .links
+        .iter()
+        .map(|link| (link.url.path(), link.nofollow))
+        .collect();
+    assert_eq!(nofollow, [("/followed", false), ("/sponsored", true)]);
+
+    page.add_x_robots_tag("otherbot: none", "recursive_scraper");
+    assert!(!page.nofollow);
+    page.add_x_robots_tag(
+        "unavailable_after: 25 Jun 2010 15:00:00 PST",
+        "recursive_scraper",
+    );
+    assert!(!page.nofollow);
+    page.add_x_robots_tag("Recursive_Scraper: nofollow", "recursive_scraper");
+    assert!(page.nofollow);
+    Ok(())
+}
+
+#[test]
+fn canonicalize_test() -> Result<()> {
+    let canonicalizer = Canonicalizer {
+        strip_params: vec![Regex::new("^utm_")?, Regex::new("^sessionid$")?],
+        sort_query: true,
+        trailing_slash: TrailingSlash::Remove,
+        ..Default::default()
+    };
+    let canonical = |url: &str| canonicalizer.canonicalize(Url::parse(url).unwrap());
+    // The problem documented by `url_slash_test`.
+    assert_eq!(
+        canonical("https://sites.duke.edu/intersections/vocabulary-lessons/"),
+        canonical("https://sites.duke.edu/intersections/vocabulary-lessons")
+    );
+    assert_eq!(
+        canonical("HTTPS://Example.COM:443/a/?utm_source=x&b=2&a=1&sessionid=3").as_str(),
+        "https://example.com/a?a=1&b=2"
+    );
+    assert_eq!(
+        canonical("http://example.com:8080/?utm_medium=y").as_str(),
+        "http://example.com:8080/"
+    );
+
+    let canonicalizer = Canonicalizer {
+        trailing_slash: TrailingSlash::Add,
+        ..Default::default()
+    };
+    let canonical = |url: &str| canonicalizer.canonicalize(Url::parse(url).unwrap());
+    assert_eq!(
+        canonical("https://example.com/a?b=1&a=2").as_str(),
+        "https://example.com/a/?b=1&a=2"
+    );
+    assert_eq!(
+        canonical("https://example.com/a.html").as_str(),
+        "https://example.com/a.html"
+    );
+
+    let html = r#"<link rel="canonical" href="/canonical#top">"#;
+    let page = links_from_html(&Url::parse("https://example.com/a?b=1")?, html.into());
+    assert_eq!(
+        page.canonical,
+        Some(Url::parse("https://example.com/canonical")?)
+    );
+    Ok(())
+}
+
+#[test]
+fn duplicate_test() -> Result<()> {
+    // Stable across runs, unlike `DefaultHasher`.
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    let text = "The quick brown fox jumps over the lazy dog while the cat sleeps \
+        on the warm windowsill and the birds sing in the tall green trees \
+        behind the old red barn at the end of the long country road.";
+    let similar = text.replace("sleeps", "naps");
+    let different = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do \
+        eiusmod tempor incididunt ut labore et dolore magna aliqua.";
+    let hash = |text| simhash(text).unwrap();
+    assert!(hamming_distance(hash(text), hash(&similar)) < 16);
+    assert!(hamming_distance(hash(text), hash(different)) > 16);
+    // Pages without text are never near duplicates.
+    assert_eq!(simhash(" <> "), None);
+
+    assert_eq!(
+        to_hex(&sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    assert_eq!(from_hex(&to_hex(&sha1(b"abc"))), Some(sha1(b"abc")));
+    assert_eq!(from_hex("a3f1c29e0b7d5e44"), None);
+    let mut record = Record::default();
+    let digest = sha1(text.as_bytes());
+    assert_eq!(record.check_duplicate(0, digest, None, None), None);
+    assert_eq!(record.check_duplicate(1, digest, None, None), Some(0));
+    let (near, far) = (simhash(text), simhash(&similar));
+    assert_eq!(record.check_duplicate(2, sha1(b"2"), near, Some(16)), None);
+    assert_eq!(record.check_duplicate(3, sha1(b"3"), far, None), None);
+    assert_eq!(
+        record.check_duplicate(4, sha1(b"4"), far, Some(16)),
+        Some(2)
+    );
+    assert_eq!(record.check_duplicate(5, sha1(b"5"), None, Some(16)), None);
+    assert_eq!(record.check_duplicate(6, sha1(b"6"), None, Some(16)), None);
+    assert_eq!(record.duplicates, [(1, 0), (4, 2)].into());
+    // Found through the bands as well as by comparing with every page.
+    for distance in [0, 3, 16, 63] {
+        let mut record = Record::default();
+        for url_id in 0..200 {
+            let simhash = fnv1a(&[url_id as u8]) ^ fnv1a(&[url_id as u8, 1]) << 7;
+            let brute = record
+                .simhashes
+                .iter()
+                .find(|(_, other)| hamming_distance(simhash, **other) <= distance)
+                .map(|(id, _)| *id);
+            let found = record.check_duplicate(
+                url_id,
+                sha1(&[url_id as u8]),
+                Some(simhash),
+                Some(distance),
+            );
+            assert_eq!(found, brute, "{distance} {url_id}");
+        }
+    }
+
+    let deserialized: Record = toml::from_str(&toml::to_string_pretty(&record)?)?;
+    assert_eq!(deserialized.digests, record.digests);
+    assert_eq!(deserialized.simhashes, record.simhashes);
+    assert_eq!(deserialized.duplicates, record.duplicates);
+    Ok(())
+}
+
+#[tokio::test]
+async fn max_depth_test() -> Result<()> {
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .max_depth(1)
+            .log_dir("dne/depth".into()),
+    );
+    scheduler.add_pending(Url::parse("https://example.com/")?);
+    scheduler.add_href(Url::parse("https://example.com/a")?, Some(0));
+    scheduler.add_href(Url::parse("https://example.com/a/b")?, Some(1));
+    scheduler.finish().await;
+    let queue: Queue = toml::from_str(&std::fs::read_to_string("dne/depth/queue.toml")?)?;
+    assert_eq!(queue.pending, [0, 1]);
+    let record: Record = toml::from_str(&std::fs::read_to_string("dne/depth/record.toml")?)?;
+    assert_eq!(record.depths, [(0, 0), (1, 1), (2, 2)].into());
+    assert_eq!(record.parents, [(1, 0), (2, 1)].into());
+    Ok(())
+}
+
+#[tokio::test]
+async fn budget_test() -> Result<()> {
+    let budget = Budget {
+        max_bytes: Some(1024),
+        max_time: Some(Duration::from_secs(60)),
+        ..Default::default()
+    };
+    assert_eq!(budget.exceeded(100, 1023, Duration::from_secs(59)), None);
+    assert_eq!(
+        budget.exceeded(100, 1024, Duration::from_secs(59)),
+        Some(StopReason::Bytes)
+    );
+    assert_eq!(
+        budget.exceeded(100, 0, Duration::from_secs(60)),
+        Some(StopReason::Time)
+    );
+
+    let budget = Budget {
+        max_requests: Some(0),
+        ..Default::default()
+    };
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .budget(budget)
+            .log_dir("dne/budget".into()),
+    );
+    scheduler.add_pending(Url::parse("https://www.rust-lang.org")?);
+    scheduler.recursion().await;
+    assert_eq!(scheduler.stopped_by(), Some(StopReason::Requests));
+    let queue: Queue = toml::from_str(&std::fs::read_to_string("dne/budget/queue.toml")?)?;
+    assert_eq!(queue.pending, [0]);
+    let record: Record = toml::from_str(&std::fs::read_to_string("dne/budget/record.toml")?)?;
+    assert_eq!(record.stopped_by, Some(StopReason::Requests));
+    Ok(())
+}
+
+#[test]
+fn retry_test() -> Result<()> {
+    assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon"), None);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, "30".parse()?);
+    let error =
+        |status| RequestError::from(&anyhow::Error::from(StatusError::new(status, &headers)));
+    let too_many = error(StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(too_many.kind, ErrorKind::TooManyRequests);
+    assert_eq!(too_many.retry_after, Some(Duration::from_secs(30)));
+    let server = error(StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(server.kind, ErrorKind::Server);
+    assert_eq!(server.retry_after, None);
+    let not_found = error(StatusCode::NOT_FOUND);
+    assert_eq!(not_found.kind, ErrorKind::Client);
+    assert_eq!(error(StatusCode::REQUEST_TIMEOUT).kind, ErrorKind::Timeout);
+
+    let policy = RetryPolicy {
+        max_attempts: 4,
+        delay: Duration::from_secs(2),
+        max_delay: Duration::from_secs(5),
+    };
+    assert_eq!(policy.backoff(1, &not_found), None);
+    for (attempts, max) in [(1, 2), (2, 4), (3, 5)] {
+        let backoff = policy.backoff(attempts, &server).unwrap();
+        let max = Duration::from_secs(max);
+        assert!(max / 2 <= backoff && backoff <= max, "{backoff:?}");
+    }
+    assert_eq!(policy.backoff(4, &server), None);
+    // `Retry-After` is clamped to `max_delay`.
+    assert_eq!(policy.backoff(1, &too_many), Some(Duration::from_secs(5)));
+
+    let huge = parse_retry_after("18446744073709551615").unwrap();
+    assert_eq!(huge, Duration::from_secs(u64::MAX));
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, "18446744073709551615".parse()?);
+    let too_many = RequestError::from(&anyhow::Error::from(StatusError::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        &headers,
+    )));
+    assert_eq!(too_many.retry_after, Some(huge));
+    assert_eq!(policy.retry_after(&too_many), Some(Duration::from_secs(5)));
+    assert_eq!(policy.backoff(1, &too_many), Some(Duration::from_secs(5)));
+    assert!(Instant::now()
+        .checked_add(policy.backoff(3, &too_many).unwrap())
+        .is_some());
+    Ok(())
+}
+
+#[test]
+fn metadata_test() -> Result<()> {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let at = |seconds| rfc3339(UNIX_EPOCH + Duration::from_secs(seconds));
+    assert_eq!(at(0), "1970-01-01T00:00:00Z");
+    assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+    assert_eq!(at(1_700_000_000), "2023-11-14T22:13:20Z");
+
+    let mut table = MetadataTable::default();
+    table.0.insert(
+        3,
+        Metadata {
+            status: Some(200),
+            content_type: Some("text/html; charset=utf-8".into()),
+            content_length: Some(1024),
+            final_url: Some("https://example.com/".into()),
+            ..Metadata::new(SystemTime::now(), Duration::from_millis(120))
+        },
+    );
+    table.0.insert(
+        4,
+        Metadata {
+            error: Some("connection refused".into()),
+            ..Metadata::new(SystemTime::now(), Duration::from_secs(10))
+        },
+    );
+    let toml = toml::to_string_pretty(&table)?;
+    println!("{toml}");
+    let deserialized: MetadataTable = toml::from_str(&toml)?;
+    assert_eq!(deserialized.0, table.0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn warc_test() -> Result<()> {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "text/html".parse()?);
+    headers.insert("transfer-encoding", "chunked".parse()?);
+    let exchange = Exchange {
+        url: Url::parse("https://example.com:8080/a?b=c")?,
+        version: reqwest::Version::HTTP_11,
+        status: StatusCode::OK,
+        headers,
+        body: "<p>Hi</p>".into(),
+    };
+    let fields = [("outlink", "https://example.com/".to_owned())];
+    let date = "2023-11-14T22:13:20Z".to_owned();
+    let [request, response, metadata] = exchange_records(&exchange, "agent", date, &fields);
+    assert_eq!(
+        request.block,
+        b"GET /a?b=c HTTP/1.1\r\nhost: example.com:8080\r\nuser-agent: agent\r\naccept: */*\r\n\r\n"
+    );
+    assert_eq!(
+        response.block,
+        b"HTTP/1.1 200 OK\r\ncontent-type: text/html\r\n\r\n<p>Hi</p>"
+    );
+    assert_eq!(metadata.block, b"outlink: https://example.com/\r\n");
+    assert_eq!(request.concurrent_to.as_ref(), Some(&response.id));
+    let bytes = String::from_utf8(response.to_bytes())?;
+    assert!(bytes.starts_with("WARC/1.1\r\nWARC-Type: response\r\n"));
+    assert!(bytes.contains("WARC-Target-URI: https://example.com:8080/a?b=c\r\n"));
+    assert!(bytes.contains("Content-Length: 53\r\n\r\nHTTP/1.1 200 OK"));
+    assert!(bytes.contains(&format!(
+        "WARC-Payload-Digest: sha1:{}\r\n",
+        data_encoding::BASE32.encode(&sha1(b"<p>Hi</p>"))
+    )));
+    assert!(bytes.ends_with("<p>Hi</p>\r\n\r\n"));
+
+    _ = std::fs::remove_dir_all("dne/warc");
+    let mut writer = WarcWriter::new(WarcConfig {
+        dir: "dne/warc".into(),
+        gzip: true,
+        max_size: 1,
+    });
+    let first = writer.path();
+    assert_ne!(WarcWriter::new(WarcConfig::default()).path(), first);
+    writer.write(&[request, response, metadata]).await?;
+    let [request, response, metadata] =
+        exchange_records(&exchange, "agent", "2023-11-14T22:13:21Z".into(), &[]);
+    writer.write(&[request, response, metadata]).await?;
+    assert_ne!(writer.path(), first);
+    let mut text = String::new();
+    MultiGzDecoder::new(std::fs::File::open(first)?).read_to_string(&mut text)?;
+    assert_eq!(text.matches("WARC/1.1\r\n").count(), 4);
+    assert!(text.contains("WARC-Type: warcinfo\r\n"));
+
+    // Bodies streamed to disk are copied from their file.
+    let payload = "dne/warc/0.part";
+    std::fs::write(payload, "0123456789".repeat(10_000))?;
+    let streamed = Exchange {
+        body: Default::default(),
+        ..exchange
+    };
+    let [request, mut response, metadata] =
+        exchange_records(&streamed, "agent", "2023-11-14T22:13:22Z".into(), &[]);
+    response.payload_file = Some((payload.into(), 100_000));
+    writer.write(&[request, response, metadata]).await?;
+    let mut text = String::new();
+    MultiGzDecoder::new(std::fs::File::open(writer.path())?).read_to_string(&mut text)?;
+    let head = "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\n\r\n";
+    assert!(text.contains(&format!(
+        "Content-Length: {}\r\n\r\n{head}{}\r\n\r\n",
+        head.len() + 100_000,
+        "0123456789".repeat(10_000)
+    )));
+    Ok(())
+}
+
+#[tokio::test]
+async fn html_size_test() -> Result<()> {
+    let dir = "dne/html_size";
+    _ = std::fs::remove_dir_all(dir);
+    // The byte order mark is dropped when decoding.
+    let body = "\u{feff}<p>Hi</p>";
+    let address = serve(vec![format!(
+        "HTTP/1.1 200 OK\r\ncontent-type: text/html\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    )])
+    .await?;
+    let cfg = SchedulerConfig::default()
+        .log_dir(dir.into())
+        .ignore_robots()
+        .disregard_html();
+    let mut scheduler = Scheduler::new(cfg);
+    scheduler.add_pending(Url::parse(&format!("http://{address}/"))?);
+    scheduler.recursion().await;
+    let metadata: MetadataTable =
+        toml::from_str(&std::fs::read_to_string(format!("{dir}/metadata.toml"))?)?;
+    assert_eq!(metadata.0[&0].size, Some(body.len() as u64));
+    Ok(())
+}
+
+#[test]
+fn mirror_path_test() -> Result<()> {
+    let path = |url: &str, extension| {
+        mirror_path(&Url::parse(url).unwrap(), extension)
+            .to_string_lossy()
+            .into_owned()
+    };
+    assert_eq!(path("https://example.com", None), "example.com/index.html");
+    assert_eq!(
+        path("https://example.com/a/b/", None),
+        "example.com/a/b/index.html"
+    );
+    assert_eq!(
+        path("https://example.com/a/b", None),
+        "example.com/a/b.html"
+    );
+    assert_eq!(
+        path("https://example.com/a/b.htm", None),
+        "example.com/a/b.htm"
+    );
+    assert_eq!(
+        path("http://example.com:8080/a.png", Some(".png")),
+        "example.com+8080/a.png"
+    );
+    assert_eq!(
+        path("https://example.com/search?q=a/b:c", None),
+        "example.com/search@q=a%2Fb%3Ac.html"
+    );
+    // Non-HTML gets the extension of its content type if its path has none.
+    assert_eq!(
+        path("https://example.com/image?id=3", Some(".png")),
+        "example.com/image@id=3.png"
+    );
+    assert_eq!(
+        path("https://example.com/a.jpg", Some(".png")),
+        "example.com/a.jpg"
+    );
+    // `%` is escaped too, so that no two URLs share a path.
+    assert_ne!(
+        path("https://example.com/a%2Fb", Some("")),
+        path("https://example.com/a%252Fb", Some(""))
+    );
+    assert_eq!(
+        path("https://example.com/100%25", Some("")),
+        "example.com/100%2525"
+    );
+    let long = path(
+        &format!("https://example.com/{}.png", "a".repeat(300)),
+        Some(".png"),
+    );
+    let name = long.strip_prefix("example.com/").unwrap();
+    assert!(name.len() <= 200 && name.ends_with(".png"), "{name}");
+    assert_ne!(
+        long,
+        path(
+            &format!("https://example.com/{}.png", "a".repeat(301)),
+            Some(".png")
+        )
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirror_collision_test() -> Result<()> {
+    let root = std::path::Path::new("dne/mirror_collision");
+    _ = std::fs::remove_dir_all(root);
+    let save = |url: &'static str| {
+        let path = root.join(mirror_path(&Url::parse(url).unwrap(), Some("")));
+        async move {
+            let (path, moved) = make_room(root, &path).await?;
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, url)?;
+            Ok((path, moved))
+        }
+    };
+    let file = |path: &str| std::fs::read_to_string(root.join(path)).unwrap();
+
+    // A file where a directory is needed moves into it.
+    let (path, moved) = save("https://example.com/a").await?;
+    assert_eq!(path, root.join("example.com/a"));
+    assert!(moved.is_empty());
+    let (path, moved) = save("https://example.com/a/b").await?;
+    assert_eq!(path, root.join("example.com/a/b"));
+    assert_eq!(
+        moved,
+        [(root.join("example.com/a"), root.join("example.com/a/index"))]
+    );
+    assert_eq!(file("example.com/a/index"), "https://example.com/a");
+    assert_eq!(file("example.com/a/b"), "https://example.com/a/b");
+
+    // A file whose path is a directory is saved in it.
+    save("https://example.com/c.d/e").await?;
+    let (path, moved) = save("https://example.com/c.d").await?;
+    assert_eq!(path, root.join("example.com/c.d/index.d"));
+    assert!(moved.is_empty());
+    assert_eq!(file("example.com/c.d/e"), "https://example.com/c.d/e");
+    assert_eq!(file("example.com/c.d/index.d"), "https://example.com/c.d");
+    Ok(())
+}
+
+#[test]
+fn rewrite_links_test() -> Result<()> {
+    let mut record = Record::default();
+    for url in [
+        "https://example.com/a/",
+        "https://example.com/img.png",
+        "https://example.com/old",
+        "https://example.com/new",
+    ] {
+        record.check_add_url(Url::parse(url)?).unwrap();
+    }
+    record.redirects.insert(2, 3);
+    for (id, path) in [
+        (0, "mirror/example.com/a/index.html"),
+        (1, "mirror/example.com/img.png"),
+        (3, "mirror/example.com/new.html"),
+    ] {
+        record.paths.insert(id, path.into());
+    }
+    let local = LocalPaths::from_record(&record, true);
+    let html = r##"<html><head><base href="/a/"></head><body>
+<a href="../img.png#top">Image</a>
+<img src='b.png' srcset="/img.png 2x, c.png 1x" alt="src=/img.png">
+<a href=/old>Old</a> <a href=/b>B</a> <a HREF="https://other.com/?a=1&amp;b=2">Other</a>
+<a href="#here">Here</a> <a href="mailto:a@example.com">Mail</a>
+</body></html>"##;
+    let url = Url::parse("https://example.com/a/")?;
+    let path = std::path::Path::new("mirror/example.com/a/index.html");
+    let canonicalizer = Canonicalizer::default();
+    let rewritten = local.rewrite_html(html, &url, path, &canonicalizer);
+    assert_eq!(
+        rewritten,
+        r##"<html><head></head><body>
+<a href="../img.png#top">Image</a>
+<img src='https://example.com/a/b.png' srcset="../img.png 2x, https://example.com/a/c.png 1x" alt="src=/img.png">
+<a href="../new.html">Old</a> <a href="https://example.com/b">B</a> <a HREF="https://other.com/?a=1&amp;b=2">Other</a>
+<a href="#here">Here</a> <a href="mailto:a@example.com">Mail</a>
+</body></html>"##
+    );
+    assert_eq!(
+        local.rewrite_html(&rewritten, &url, path, &canonicalizer),
+        rewritten
+    );
+
+    // Quoted `>`, comments and scripts.
+    let html = r#"<a title="a > b" href="/old">Old</a><!-- <a href="/old"> -->
+<script src="/old">if (a<b) { x = '<a href="/old">'; }</script><p title='src=/old'>"#;
+    assert_eq!(
+        local.rewrite_html(html, &url, path, &canonicalizer),
+        r#"<a title="a > b" href="../new.html">Old</a><!-- <a href="/old"> -->
+<script src="../new.html">if (a<b) { x = '<a href="/old">'; }</script><p title='src=/old'>"#
+    );
+
+    // Outside a mirror, a link that happens to name a saved file is not local.
+    let mut record = Record::default();
+    for url in ["https://example.com/a/", "https://example.com/b/"] {
+        record.check_add_url(Url::parse(url)?).unwrap();
+    }
+    record.paths.insert(0, "html/0.html".into());
+    record.paths.insert(1, "html/1.html".into());
+    let path = std::path::Path::new("html/0.html");
+    let html = r#"<a href="1.html">1</a> <a href="/b/">B</a>"#;
+    assert_eq!(
+        LocalPaths::from_record(&record, false).rewrite_html(html, &url, path, &canonicalizer),
+        r#"<a href="https://example.com/a/1.html">1</a> <a href="1.html">B</a>"#
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn raw_html_rewrite_test() -> Result<()> {
+    let dir = "dne/raw_rewrite";
+    let page = format!("{dir}/mirror/example.com/index.html");
+    let mut record = Record::default();
+    for url in ["https://example.com/", "https://example.com/日本"] {
+        record.check_add_url(Url::parse(url)?).unwrap();
+    }
+    record.paths.insert(0, page.clone());
+    record
+        .paths
+        .insert(1, format!("{dir}/mirror/example.com/nihon.html"));
+    // The charset was only in `Content-Type`.
+    let mut metadata = MetadataTable::default();
+    metadata.0.insert(
+        0,
+        Metadata {
+            encoding: Some(SHIFT_JIS.name().into()),
+            ..Metadata::new(std::time::SystemTime::now(), Duration::ZERO)
+        },
+    );
+    save_file(format!("{dir}/record.toml"), toml::to_string(&record)?).await?;
+    save_file(
+        format!("{dir}/queue.toml"),
+        toml::to_string(&Queue::default())?,
+    )
+    .await?;
+    save_file(format!("{dir}/metadata.toml"), toml::to_string(&metadata)?).await?;
+    let (body, _, _) = SHIFT_JIS.encode(r#"<p>日本語</p><a href="/日本">日本</a>"#);
+    save_file(&page, &body).await?;
+
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .log_dir(dir.into())
+            .output(Output::Mirror)
+            .raw_html()
+            .rewrite_links(),
+    );
+    scheduler.resume().await?;
+    scheduler.rewrite_links().await;
+    let saved = std::fs::read(&page)?;
+    let (text, _, _) = SHIFT_JIS.decode(&saved);
+    assert_eq!(text, r#"<p>日本語</p><a href="nihon.html">日本</a>"#);
+    Ok(())
+}
+
+#[test]
+fn extension_test() -> Result<()> {
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    };
+    let url = Url::parse("https://x.com/image?id=3")?;
+    assert_eq!(
+        extension(&headers(&[("content-type", "image/PNG; q=1")]), &url),
+        ".png"
+    );
+    assert_eq!(extension(&headers(&[]), &url), "");
+    let disposition = headers(&[
+        ("content-type", "application/octet-stream"),
+        (
+            "content-disposition",
+            r#"attachment; filename="../../report.PDF""#,
+        ),
+    ]);
+    assert_eq!(extension(&disposition, &url), ".pdf");
+    let url = Url::parse("https://x.com/a.b/c")?;
+    assert_eq!(extension(&headers(&[]), &url), "");
+    let url = Url::parse("https://x.com/a/archive.tar.gz?v=2")?;
+    assert_eq!(extension(&headers(&[]), &url), ".gz");
+    let url = Url::parse("https://x.com/a/file.%2F..%2Fx")?;
+    assert_eq!(extension(&headers(&[]), &url), "");
+    Ok(())
+}
+
+#[test]
+fn mime_policy_test() {
+    assert!(glob_match("image/*", "image/png"));
+    assert!(glob_match("*/*+xml", "image/svg+xml"));
+    assert!(glob_match("Application/PDF", "application/pdf"));
+    assert!(!glob_match("image/*", "video/mp4"));
+    assert!(!glob_match("text/html", "text/html5"));
+
+    let policy = MimePolicy::default();
+    assert!(policy.parses("text/html; charset=utf-8"));
+    assert!(!policy.parses("application/pdf"));
+    assert!(policy.saves("application/zip"));
+
+    let policy = MimePolicy {
+        save: vec![
+            "image/*".to_owned(),
+            "application/pdf".to_owned(),
+            "text/html".to_owned(),
+        ],
+        reject: vec!["image/gif".to_owned()],
+        ..Default::default()
+    };
+    assert!(policy.saves("image/png"));
+    assert!(policy.saves("application/pdf"));
+    assert!(!policy.saves("image/gif"));
+    assert!(!policy.downloads("image/gif"));
+    assert!(!policy.downloads("video/mp4"));
+    assert!(!policy.downloads(""));
+    assert!(policy.downloads("text/html"));
+
+    // Patterns are lowercased once when set on the config.
+    let cfg = SchedulerConfig::default().mime(MimePolicy {
+        save: vec!["Image/*".to_owned()],
+        ..Default::default()
+    });
+    assert_eq!(cfg.mime.save, ["image/*"]);
+    assert!(cfg.mime.saves("IMAGE/PNG"));
+}
+
+#[tokio::test]
+async fn streamed_body_test() -> Result<()> {
+    let chunks: [&[u8]; 3] = [b"GIF89a", b"", b"\x01\x00\x01\x00"];
+    let mut hasher = Sha1Hasher::default();
+    for chunk in chunks {
+        hasher.update(chunk);
+    }
+    let whole = chunks.concat();
+    let digest = hasher.finish();
+    assert_eq!(digest, sha1(&whole));
+    assert_eq!(Body::Memory(whole.clone().into()).digest(), sha1(&whole));
+
+    save_file("dne/downloads/0.part", &whole).await?;
+    move_file("dne/downloads/0.part", "dne/other/0.gif").await?;
+    assert_eq!(std::fs::read("dne/other/0.gif")?, whole);
+    assert!(!std::path::Path::new("dne/downloads/0.part").exists());
+
+    save_file("dne/downloads/1.part", &whole).await?;
+    let body = Body::File {
+        path: "dne/downloads/1.part".into(),
+        size: whole.len() as u64,
+        digest,
+    };
+    assert_eq!(body.size(), 10);
+    body.discard().await;
+    assert!(!std::path::Path::new("dne/downloads/1.part").exists());
+    Ok(())
+}
+
+#[test]
+fn charset_test() {
+    let (body, _, _) = SHIFT_JIS.encode("<meta charset=\"Shift_JIS\"><p>日本語</p>");
+    assert_eq!(sniff_encoding(&body, Some("text/html")), SHIFT_JIS);
+    let (text, encoding) = decode_html(&body, None);
+    assert_eq!(encoding, SHIFT_JIS);
+    assert!(text.ends_with("<p>日本語</p>"));
+    // The header takes precedence over `<meta>`, and the BOM over both.
+    assert_eq!(sniff_encoding(&body, Some("text/html; charset=GBK")), GBK);
+    let with_bom = [b"\xEF\xBB\xBF".as_slice(), &body].concat();
+    assert_eq!(
+        sniff_encoding(&with_bom, Some("text/html; charset=GBK")),
+        UTF_8
+    );
+
+    let pragma = br#"<!-- <meta charset="utf-8"> --><META HTTP-EQUIV='Content-Type' content='text/html; charset="gb2312"'>"#;
+    assert_eq!(sniff_encoding(pragma, None), GBK);
+    let no_pragma = b"<meta content=\"text/html; charset=gb2312\"><p>caf\xE9</p>";
+    assert_eq!(sniff_encoding(no_pragma, None), WINDOWS_1252);
+    assert_eq!(sniff_encoding(b"<meta charset=utf-16le>", None), UTF_8);
+    assert_eq!(sniff_encoding("<p>café</p>".as_bytes(), None), UTF_8);
+
+    let (gbk, _, _) = GBK.encode("<meta charset=gbk>中文");
+    let (text, encoding) = decode_html(&gbk, None);
+    assert_eq!(encode_html(&text, encoding, false), gbk.into_owned());
+    let utf_8 = encode_html(&text, UTF_8, encoding != UTF_8);
+    assert_eq!(utf_8, "\u{FEFF}<meta charset=gbk>中文".as_bytes());
+    assert_eq!(decode_html(&utf_8, None), (text, UTF_8));
+}
+
+#[test]
+fn recrawl_test() -> Result<()> {
+    let urls = ["https://a.com/", "https://a.com/b", "https://a.com/c"].map(Url::parse);
+    let mut record = Record::default();
+    for url in &urls {
+        record.check_add_url(url.clone()?).unwrap();
+    }
+    record.set_parent(1, 0);
+    record.set_parent(2, 0);
+    let mut metadata = MetadataTable::default();
+    for (id, digest) in [(0, "00"), (1, "01"), (2, "02")] {
+        let entry = Metadata {
+            digest: Some(digest.into()),
+            ..Default::default()
+        };
+        metadata.0.insert(id, entry);
+    }
+    metadata.0.get_mut(&0).unwrap().etag = Some("\"v1\"".into());
+    metadata.0.get_mut(&1).unwrap().last_modified = Some("Tue, 14 Nov 2023 20:00:00 GMT".into());
+    let previous = PreviousCrawl::new(record, metadata);
+    assert_eq!(
+        previous.conditional_headers(0),
+        [(IF_NONE_MATCH, "\"v1\"".to_owned())]
+    );
+    assert_eq!(
+        previous.conditional_headers(1),
+        [(
+            IF_MODIFIED_SINCE,
+            "Tue, 14 Nov 2023 20:00:00 GMT".to_owned()
+        )]
+    );
+    assert!(previous.conditional_headers(2).is_empty());
+    assert_eq!(previous.children(0), [1, 2]);
+
+    // URLs found again keep their ids, and new ones come after.
+    let mut record = Record::default();
+    record.reserve_ids(&previous.record);
+    assert_eq!(
+        record
+            .check_add_url(Url::parse("https://a.com/b")?)
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        record
+            .check_add_url(Url::parse("https://a.com/d")?)
+            .unwrap(),
+        3
+    );
+    assert_eq!(
+        record.check_add_url(Url::parse("https://a.com/")?).unwrap(),
+        0
+    );
+    assert_eq!(
+        record
+            .check_add_url(Url::parse("https://a.com/e")?)
+            .unwrap(),
+        4
+    );
+
+    let mut changes = Changes::default();
+    changes.add(0, previous.digest(0), "00");
+    changes.add(1, previous.digest(1), "11");
+    changes.add(3, previous.digest(3), "03");
+    assert_eq!(
+        changes.report(&previous, &record),
+        ChangeReport {
+            new: vec!["https://a.com/d".into()],
+            changed: vec!["https://a.com/b".into()],
+            unchanged: vec!["https://a.com/".into()],
+            vanished: vec!["https://a.com/c".into()],
+            unreached: Vec::new(),
+        }
+    );
+
+    // Unless requested, URLs are not known to have vanished if the crawl stopped early.
+    record.stopped_by = Some(StopReason::Time);
+    let report = changes.report(&previous, &record);
+    assert!(report.vanished.is_empty());
+    assert_eq!(report.unreached, ["https://a.com/c"]);
+    record.attempts.insert(2, 1);
+    let report = changes.report(&previous, &record);
+    assert_eq!(report.vanished, ["https://a.com/c"]);
+    assert!(report.unreached.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn resume_recrawl_test() -> Result<()> {
+    let dir = "dne/resume_recrawl";
+    _ = std::fs::remove_dir_all(dir);
+    let mut record = Record::default();
+    let mut metadata = MetadataTable::default();
+    for url in ["https://a.com/", "https://a.com/b", "https://a.com/c"] {
+        let id = record.check_add_url(Url::parse(url)?).unwrap();
+        let entry = Metadata {
+            digest: Some(format!("{id:02}")),
+            ..Default::default()
+        };
+        metadata.0.insert(id, entry);
+    }
+    save_file(format!("{dir}/record.toml"), toml::to_string(&record)?).await?;
+    save_file(format!("{dir}/metadata.toml"), toml::to_string(&metadata)?).await?;
+    let stopped_run = |url: &'static str, resume: bool| async move {
+        let mut scheduler = Scheduler::new(SchedulerConfig::default().log_dir(dir.into()));
+        if resume {
+            scheduler.resume().await.unwrap();
+        } else {
+            scheduler.recrawl().await.unwrap();
+        }
+        scheduler.add_pending(Url::parse(url).unwrap());
+        scheduler
+            .stop_handle()
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        scheduler.recursion().await;
+    };
+
+    stopped_run("https://a.com/b", false).await;
+    std::fs::remove_file(format!("{dir}/changes.toml"))?;
+    // The record of the previous crawl is overwritten by now.
+    stopped_run("https://a.com/d", true).await;
+    let queue: Queue = toml::from_str(&std::fs::read_to_string(format!("{dir}/queue.toml"))?)?;
+    assert!(queue.recrawl);
+    let mut pending = queue.pending;
+    pending.sort();
+    assert_eq!(pending, [1, 3]);
+    let report: ChangeReport =
+        toml::from_str(&std::fs::read_to_string(format!("{dir}/changes.toml"))?)?;
+    assert_eq!(report.unreached.len(), 3);
+    Ok(())
+}
+
+#[test]
+fn config_file_test() -> Result<()> {
+    let config_file: ConfigFile = toml::from_str(
+        r#"
+start_urls = ["https://a.com/"]
+connection_timeout = 3000
+delay = 1000
+filter = "^https://a\\.com"
+follow = ["anchor", "form"]
+output = "mirror"
+
+[[host_delays]]
+host = 'b\.com'
+delay = 100
+
+[canonicalizer]
+strip_params = ["^utm_"]
+trailing_slash = "remove"
+
+[mime]
+save = ["image/*"]
+
+[budget]
+max_time = 60
+
+[retry]
+max_attempts = 5
+"#,
+    )?;
+    assert_eq!(
+        config_file.start_urls.as_deref(),
+        Some(&["https://a.com/".to_owned()][..])
+    );
+    let cfg = config_file.apply(SchedulerConfig::default())?;
+    assert_eq!(cfg.connection_timeout, Duration::from_secs(3));
+    assert_eq!(cfg.delay, Duration::from_secs(1));
+    assert!(cfg.filter.is_match("https://a.com/b"));
+    assert_eq!(
+        cfg.follow,
+        BTreeSet::from([LinkKind::Anchor, LinkKind::Form])
+    );
+    assert_eq!(cfg.output, Output::Mirror);
+    assert_eq!(cfg.host_delays[0].0.as_str(), r"b\.com");
+    assert_eq!(cfg.host_delays[0].1, Duration::from_millis(100));
+    assert_eq!(cfg.canonicalizer.trailing_slash, TrailingSlash::Remove);
+    assert!(!cfg.canonicalizer.sort_query);
+    assert_eq!(cfg.mime.parse, ["text/html"]);
+    assert_eq!(cfg.mime.save, ["image/*"]);
+    assert_eq!(cfg.budget.max_time, Some(Duration::from_secs(60)));
+    assert_eq!(cfg.retry.max_attempts, 5);
+    assert_eq!(cfg.retry.delay, RetryPolicy::default().delay);
+
+    // Dumped configs read back the same.
+    let dumped = toml::to_string(&ConfigFile::from(&cfg))?;
+    let cfg_again = toml::from_str::<ConfigFile>(&dumped)?.apply(SchedulerConfig::default())?;
+    assert_eq!(toml::to_string(&ConfigFile::from(&cfg_again))?, dumped);
+
+    // No limit on the HTML size is written as 0.
+    let unlimited = SchedulerConfig {
+        max_html_size: None,
+        ..SchedulerConfig::default()
+    };
+    let dumped = toml::to_string(&ConfigFile::from(&unlimited))?;
+    let cfg_again = toml::from_str::<ConfigFile>(&dumped)?.apply(SchedulerConfig::default())?;
+    assert_eq!(cfg_again.max_html_size, None);
+    assert_eq!(
+        SchedulerConfig::default().max_body_size(0).max_body_size,
+        None
+    );
+
+    assert!(toml::from_str::<ConfigFile>("dealy = 1000").is_err());
+    Ok(())
+}
+
+#[test]
+fn rules_test() -> Result<()> {
+    let rules = [
+        "exclude:glob:*.pdf",
+        "include:host:a.com",
+        "exclude:path:/private/",
+        r"include:regex:^https://b\.com/docs:v2",
+    ];
+    let mut cfg = SchedulerConfig::default().filter(Regex::new(r"^https://c\.com")?);
+    for rule in rules {
+        let parsed: Rule = rule.parse()?;
+        assert_eq!(parsed.to_string(), rule);
+        cfg = cfg.rule(parsed);
+    }
+    let parsed_rules = cfg.rules.clone();
+    assert!("include:glob".parse::<Rule>().is_err());
+    assert!("follow:host:a.com".parse::<Rule>().is_err());
+
+    let scheduler = Scheduler::new(cfg);
+    let explain = |url: &str| {
+        let (_, decision) = scheduler.explain_url(Url::parse(url).unwrap());
+        (decision.fate, decision.to_string())
+    };
+    assert_eq!(
+        explain("https://www.A.com/private/x.PDF"),
+        (
+            Fate::Exclude,
+            "excluded by rule 1 `exclude:glob:*.pdf`".into()
+        )
+    );
+    assert_eq!(
+        explain("https://a.com/private/"),
+        (
+            Fate::Follow,
+            "followed by rule 2 `include:host:a.com`".into()
+        )
+    );
+    assert_eq!(explain("https://b.com/docs:v2/").0, Fate::Follow);
+    assert_eq!(explain("https://c.com/").0, Fate::Follow);
+    assert_eq!(
+        explain("https://d.com/"),
+        (Fate::Outside, "outside the filter, matching no rule".into())
+    );
+
+    let scheduler = Scheduler::new(SchedulerConfig {
+        rules: parsed_rules,
+        ..SchedulerConfig::default().rule_order(RuleOrder::LastMatch)
+    });
+    let (_, decision) = scheduler.explain_url(Url::parse("https://a.com/private/")?);
+    assert_eq!(decision.fate, Fate::Exclude);
+    assert!(matches!(decision.reason, Reason::Rule(2, _)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn asset_filter_test() -> Result<()> {
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .asset_filter(Regex::new(r"\.(png|jpg)$")?)
+            .asset_blacklist(Regex::new(r"/pixel")?)
+            .same_host_assets()
+            .log_dir("dne/assets".into()),
+    );
+    scheduler.add_pending(Url::parse("https://example.com/")?);
+    for asset in [
+        "https://example.com/a.png",
+        "https://example.com/a.svg",
+        "https://example.com/pixel.png",
+        "https://ads.example.net/b.png",
+        "https://EXAMPLE.com/c.jpg",
+    ] {
+        scheduler.add_asset(Url::parse(asset)?, 0);
+    }
+    scheduler.finish().await;
+    let queue: Queue = toml::from_str(&std::fs::read_to_string("dne/assets/queue.toml")?)?;
+    assert_eq!(queue.pending, [0, 1, 4]);
+    // Assets filtered out are still recorded, unlike those on another host.
+    let record: Record = toml::from_str(&std::fs::read_to_string("dne/assets/record.toml")?)?;
+    assert_eq!(record.url_ids.len(), 5);
+    assert_eq!(record.parents.len(), 4);
+
+    // An asset refused on one page is requested once a page on its host finds it.
+    let mut scheduler = Scheduler::new(
+        SchedulerConfig::default()
+            .same_host_assets()
+            .log_dir("dne/assets_order".into()),
+    );
+    scheduler.add_pending(Url::parse("https://example.com/")?);
+    scheduler.add_pending(Url::parse("https://cdn.example.net/")?);
+    scheduler.add_asset(Url::parse("https://cdn.example.net/b.png")?, 0);
+    scheduler.add_asset(Url::parse("https://cdn.example.net/b.png")?, 1);
+    scheduler.finish().await;
+    let queue: Queue = toml::from_str(&std::fs::read_to_string("dne/assets_order/queue.toml")?)?;
+    assert_eq!(queue.pending, [0, 1, 2]);
+    Ok(())
+}
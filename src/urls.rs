@@ -1,16 +1,66 @@
 use std::collections::{BTreeMap, BTreeSet};
 
 use log::{debug, info};
-use reqwest::{Response, Url};
-use serde::{ser::SerializeStruct, Serialize};
+use reqwest::Url;
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    budget::StopReason,
+    digest::{from_hex, hamming_distance, to_hex, Digest},
+    retry::ErrorKind,
+};
 
 #[derive(Debug, Default)]
 pub struct Record {
     pub urls: BTreeMap<Url, usize>,
     pub url_ids: BTreeMap<usize, Url>,
+    /// Number of links followed from a start URL to reach each URL.
+    pub depths: BTreeMap<usize, usize>,
+    /// The page each URL was first found on.
+    pub parents: BTreeMap<usize, usize>,
     pub scrapes: BTreeSet<usize>,
+    /// URLs given up on.
     pub fails: BTreeSet<usize>,
+    /// Number of requests sent for each URL.
+    pub attempts: BTreeMap<usize, u32>,
+    /// Kind of the last error of each URL not successfully scraped.
+    pub errors: BTreeMap<usize, ErrorKind>,
+    /// URLs not requested because robots.txt disallows them.
+    pub disallowed: BTreeSet<usize>,
+    /// Pages with links not followed because of `nofollow`.
+    pub nofollow: BTreeSet<usize>,
+    /// Pages not saved because of `noindex`.
+    pub noindex: BTreeSet<usize>,
     pub redirects: BTreeMap<usize, usize>,
+    /// Pages whose `<link rel="canonical">` is another URL.
+    pub canonicals: BTreeMap<usize, usize>,
+    /// Canonical URLs not requested, with the page saved in their place.
+    pub stand_ins: BTreeMap<usize, usize>,
+    /// SHA-1 of the content of each saved page or asset.
+    pub digests: BTreeMap<Digest, usize>,
+    /// SimHash of the text of each saved page that has text.
+    pub simhashes: BTreeMap<usize, u64>,
+    /// `simhashes` by band, built when near duplicates are first looked for.
+    pub simhash_index: SimhashIndex,
+    /// Pages and assets not saved because their content duplicates another.
+    pub duplicates: BTreeMap<usize, usize>,
+    /// URLs not requested because their hosts used up `max_pages_per_host`.
+    pub over_budget: BTreeSet<usize>,
+    /// Content types of the pages and assets not saved
+    /// or not downloaded because of the content-type policy.
+    pub skipped_types: BTreeMap<usize, String>,
+    /// Pages and assets whose bodies are over the size limits.
+    pub oversized: BTreeSet<usize>,
+    /// Where each page or asset is saved, unless in WARC files.
+    pub paths: BTreeMap<usize, String>,
+    /// Saved pages whose links have been rewritten.
+    pub rewritten: BTreeSet<usize>,
+    /// Why the last run stopped before running out of URLs.
+    pub stopped_by: Option<StopReason>,
+    /// Ids of the URLs of a previous crawl, given back when they are found again.
+    pub reserved: BTreeMap<Url, usize>,
+    /// First id for URLs not in `reserved`.
+    pub first_new_id: usize,
 }
 
 impl Record {
@@ -21,19 +71,56 @@ impl Record {
         if let Some(index) = self.urls.get(&url) {
             return Err(*index);
         }
-        let index = self.urls.len();
+        let index = match self.reserved.get(&url) {
+            Some(index) => *index,
+            None => self
+                .url_ids
+                .keys()
+                .next_back()
+                .map_or(0, |last| last + 1)
+                .max(self.first_new_id),
+        };
         self.urls.insert(url.clone(), index);
         self.url_ids.insert(index, url);
+        self.depths.insert(index, 0);
         Ok(index)
     }
 
+    /// Keep the ids of the URLs in `previous` for when they are found again,
+    /// so that the files saved for them stay theirs.
+    pub fn reserve_ids(&mut self, previous: &Record) {
+        self.reserved = previous.urls.clone();
+        self.first_new_id = previous
+            .url_ids
+            .keys()
+            .next_back()
+            .map_or(0, |last| last + 1);
+    }
+
+    pub fn depth(&self, url_id: usize) -> usize {
+        self.depths.get(&url_id).copied().unwrap_or_default()
+    }
+
+    /// Record that `url_id` was found on the page `parent`.
+    pub fn set_parent(&mut self, url_id: usize, parent: usize) {
+        self.depths.insert(url_id, self.depth(parent) + 1);
+        self.parents.insert(url_id, parent);
+    }
+
     /// # Return
     /// `None` if the URL is already scraped.
     ///
     /// `Some(final_url_id)` otherwise.
-    pub async fn check_final_url(&mut self, url_id: usize, response: &Response) -> Option<usize> {
-        let final_url_id = match self.check_add_url(response.url().to_owned()) {
-            Ok(id) => id,
+    pub async fn check_final_url(&mut self, url_id: usize, final_url: Url) -> Option<usize> {
+        let final_url_id = match self.check_add_url(final_url) {
+            Ok(id) => {
+                // Reached the same way as the URL redirected to it.
+                self.depths.insert(id, self.depth(url_id));
+                if let Some(parent) = self.parents.get(&url_id).copied() {
+                    self.parents.insert(id, parent);
+                }
+                id
+            }
             Err(id) => {
                 if url_id != id && self.scrapes.contains(&id) {
                     debug!("{url_id}: already scraped as {id}.");
@@ -50,36 +137,302 @@ impl Record {
         Some(final_url_id)
     }
 
-    pub fn lens(&self) -> (usize, usize, usize, usize) {
+    /// If `digest`, or a SimHash at most `max_distance` bits from `simhash`,
+    /// is already recorded, record `url_id` as a duplicate and return the original.
+    ///
+    /// Otherwise, record the hashes for `url_id` and return `None`.
+    pub fn check_duplicate(
+        &mut self,
+        url_id: usize,
+        digest: Digest,
+        simhash: Option<u64>,
+        max_distance: Option<u32>,
+    ) -> Option<usize> {
+        let original = match (self.digests.get(&digest), simhash, max_distance) {
+            (Some(original), _, _) => Some(*original),
+            (None, Some(simhash), Some(max_distance)) => self.near_duplicate(simhash, max_distance),
+            _ => None,
+        };
+        match original {
+            Some(original) if original != url_id => {
+                self.duplicates.insert(url_id, original);
+                Some(original)
+            }
+            _ => {
+                self.digests.insert(digest, url_id);
+                if let Some(simhash) = simhash {
+                    match self.simhashes.insert(url_id, simhash) {
+                        None => self.simhash_index.insert(url_id, simhash),
+                        // Rebuilt on the next lookup.
+                        Some(_) => self.simhash_index = SimhashIndex::default(),
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// The first page whose SimHash is at most `max_distance` bits from `simhash`.
+    fn near_duplicate(&mut self, simhash: u64, max_distance: u32) -> Option<usize> {
+        let index = &self.simhash_index;
+        if index.bands.is_empty()
+            || index.max_distance != max_distance
+            || index.len != self.simhashes.len()
+        {
+            let mut index = SimhashIndex::new(max_distance);
+            for (url_id, simhash) in &self.simhashes {
+                index.insert(*url_id, *simhash);
+            }
+            self.simhash_index = index;
+        }
+        self.simhash_index
+            .candidates(simhash)
+            .into_iter()
+            .find(|url_id| hamming_distance(simhash, self.simhashes[url_id]) <= max_distance)
+    }
+
+    pub fn lens(&self) -> (usize, usize, usize, usize, usize, usize, usize, usize) {
         (
             self.urls.len(),
             self.scrapes.len(),
             self.fails.len(),
+            self.disallowed.len(),
+            self.nofollow.len(),
+            self.noindex.len(),
             self.redirects.len(),
+            self.duplicates.len(),
         )
     }
 }
 
+/// SimHashes split into `max_distance + 1` bands of bits,
+/// so that near duplicates are found without comparing against every page:
+/// two SimHashes at most `max_distance` bits apart
+/// are equal in at least one band.
+#[derive(Debug, Default)]
+pub struct SimhashIndex {
+    max_distance: u32,
+    /// Number of SimHashes inserted.
+    len: usize,
+    /// Ids of the pages with each value of each band.
+    bands: Vec<BTreeMap<u64, Vec<usize>>>,
+}
+
+impl SimhashIndex {
+    pub fn new(max_distance: u32) -> Self {
+        let bands = (max_distance as usize + 1).min(64);
+        Self {
+            max_distance,
+            len: 0,
+            bands: vec![BTreeMap::new(); bands],
+        }
+    }
+
+    /// The value of each band of `simhash`.
+    fn keys(&self, simhash: u64) -> Vec<u64> {
+        let bands = self.bands.len() as u32;
+        (0..bands)
+            .map(|band| {
+                let (start, end) = (band * 64 / bands, (band + 1) * 64 / bands);
+                let mask = u64::MAX >> (64 - (end - start));
+                simhash >> start & mask
+            })
+            .collect()
+    }
+
+    /// Does nothing until the index is built with [`Self::new`].
+    pub fn insert(&mut self, url_id: usize, simhash: u64) {
+        if self.bands.is_empty() {
+            return;
+        }
+        for (band, key) in self.keys(simhash).into_iter().enumerate() {
+            self.bands[band].entry(key).or_default().push(url_id);
+        }
+        self.len += 1;
+    }
+
+    /// Ids of the pages equal to `simhash` in any band, in order.
+    pub fn candidates(&self, simhash: u64) -> BTreeSet<usize> {
+        self.keys(simhash)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(band, key)| self.bands[band].get(&key))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
 impl Serialize for Record {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut seq = serializer.serialize_struct("record", 4)?;
-        seq.serialize_field("scrapes", &self.scrapes)?;
-        seq.serialize_field("fails", &self.fails)?;
-        let urls: BTreeMap<_, _> = self
-            .urls
-            .iter()
-            .map(|(url, id)| (url.to_string(), id))
-            .collect();
-        seq.serialize_field("urls", &urls)?;
-        let redirects: BTreeMap<_, _> = self
-            .redirects
-            .iter()
-            .map(|(before, after)| (before.to_string(), after))
-            .collect();
-        seq.serialize_field("redirects", &redirects)?;
-        seq.end()
+        RecordFile::from(self).serialize(serializer)
+    }
+}
+
+/// The on-disk shape of [`Record`], with the keys of maps as strings.
+#[derive(Deserialize, Serialize)]
+struct RecordFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stopped_by: Option<StopReason>,
+    scrapes: BTreeSet<usize>,
+    fails: BTreeSet<usize>,
+    #[serde(default)]
+    disallowed: BTreeSet<usize>,
+    #[serde(default)]
+    nofollow: BTreeSet<usize>,
+    #[serde(default)]
+    noindex: BTreeSet<usize>,
+    #[serde(default)]
+    over_budget: BTreeSet<usize>,
+    #[serde(default)]
+    oversized: BTreeSet<usize>,
+    #[serde(default)]
+    rewritten: BTreeSet<usize>,
+    urls: BTreeMap<String, usize>,
+    redirects: BTreeMap<String, usize>,
+    #[serde(default)]
+    attempts: BTreeMap<String, u32>,
+    #[serde(default)]
+    errors: BTreeMap<String, ErrorKind>,
+    #[serde(default)]
+    depths: BTreeMap<String, usize>,
+    #[serde(default)]
+    parents: BTreeMap<String, usize>,
+    #[serde(default)]
+    canonicals: BTreeMap<String, usize>,
+    #[serde(default)]
+    stand_ins: BTreeMap<String, usize>,
+    #[serde(default)]
+    paths: BTreeMap<String, String>,
+    #[serde(default)]
+    skipped_types: BTreeMap<String, String>,
+    #[serde(default)]
+    duplicate_of: BTreeMap<String, usize>,
+    #[serde(default)]
+    digests: BTreeMap<String, usize>,
+    #[serde(default)]
+    simhashes: BTreeMap<String, String>,
+}
+
+impl From<&Record> for RecordFile {
+    fn from(record: &Record) -> Self {
+        fn keys_to_string<K: ToString, V: Clone>(map: &BTreeMap<K, V>) -> BTreeMap<String, V> {
+            map.iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect()
+        }
+        Self {
+            stopped_by: record.stopped_by,
+            scrapes: record.scrapes.clone(),
+            fails: record.fails.clone(),
+            disallowed: record.disallowed.clone(),
+            nofollow: record.nofollow.clone(),
+            noindex: record.noindex.clone(),
+            over_budget: record.over_budget.clone(),
+            oversized: record.oversized.clone(),
+            rewritten: record.rewritten.clone(),
+            urls: keys_to_string(&record.urls),
+            redirects: keys_to_string(&record.redirects),
+            attempts: keys_to_string(&record.attempts),
+            errors: keys_to_string(&record.errors),
+            depths: keys_to_string(&record.depths),
+            parents: keys_to_string(&record.parents),
+            canonicals: keys_to_string(&record.canonicals),
+            stand_ins: keys_to_string(&record.stand_ins),
+            paths: keys_to_string(&record.paths),
+            skipped_types: keys_to_string(&record.skipped_types),
+            duplicate_of: keys_to_string(&record.duplicates),
+            digests: record
+                .digests
+                .iter()
+                .map(|(digest, id)| (to_hex(digest), *id))
+                .collect(),
+            simhashes: record
+                .simhashes
+                .iter()
+                .map(|(id, simhash)| (id.to_string(), format!("{simhash:016x}")))
+                .collect(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Record {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let file = RecordFile::deserialize(deserializer)?;
+        let mut record = Record {
+            scrapes: file.scrapes,
+            fails: file.fails,
+            disallowed: file.disallowed,
+            nofollow: file.nofollow,
+            noindex: file.noindex,
+            over_budget: file.over_budget,
+            oversized: file.oversized,
+            rewritten: file.rewritten,
+            stopped_by: file.stopped_by,
+            ..Default::default()
+        };
+        for (url, id) in file.urls {
+            let url = Url::parse(&url).map_err(D::Error::custom)?;
+            record.urls.insert(url.clone(), id);
+            record.url_ids.insert(id, url);
+        }
+        for (before, after) in file.redirects {
+            let before = before.parse().map_err(D::Error::custom)?;
+            record.redirects.insert(before, after);
+        }
+        for (id, attempts) in file.attempts {
+            let id = id.parse().map_err(D::Error::custom)?;
+            record.attempts.insert(id, attempts);
+        }
+        for (id, kind) in file.errors {
+            let id = id.parse().map_err(D::Error::custom)?;
+            record.errors.insert(id, kind);
+        }
+        for (id, depth) in file.depths {
+            let id = id.parse().map_err(D::Error::custom)?;
+            record.depths.insert(id, depth);
+        }
+        for (child, parent) in file.parents {
+            let child = child.parse().map_err(D::Error::custom)?;
+            record.parents.insert(child, parent);
+        }
+        for (page, canonical) in file.canonicals {
+            let page = page.parse().map_err(D::Error::custom)?;
+            record.canonicals.insert(page, canonical);
+        }
+        for (canonical, page) in file.stand_ins {
+            let canonical = canonical.parse().map_err(D::Error::custom)?;
+            record.stand_ins.insert(canonical, page);
+        }
+        for (id, path) in file.paths {
+            let id = id.parse().map_err(D::Error::custom)?;
+            record.paths.insert(id, path);
+        }
+        for (id, content_type) in file.skipped_types {
+            let id = id.parse().map_err(D::Error::custom)?;
+            record.skipped_types.insert(id, content_type);
+        }
+        for (duplicate, original) in file.duplicate_of {
+            let duplicate = duplicate.parse().map_err(D::Error::custom)?;
+            record.duplicates.insert(duplicate, original);
+        }
+        // Digests of other hashes, from older versions, are dropped.
+        for (digest, id) in file.digests {
+            if let Some(digest) = from_hex(&digest) {
+                record.digests.insert(digest, id);
+            }
+        }
+        for (id, simhash) in file.simhashes {
+            let id = id.parse().map_err(D::Error::custom)?;
+            let simhash = u64::from_str_radix(&simhash, 16).map_err(D::Error::custom)?;
+            record.simhashes.insert(id, simhash);
+        }
+        Ok(record)
     }
 }
@@ -0,0 +1,347 @@
+use std::{io::Write, mem::take, path::PathBuf, time::SystemTime};
+
+use anyhow::Result;
+use data_encoding::{BASE32, HEXLOWER};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::TRANSFER_ENCODING;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::{
+    digest::{sha1, Digest},
+    io::append_file,
+    metadata::rfc3339,
+    middle::Exchange,
+};
+
+pub const DEFAULT_WARC_DIR: &str = "warc";
+/// Size after which a new WARC file is started.
+pub const DEFAULT_MAX_WARC_SIZE: u64 = 1 << 30;
+/// Bytes of a payload file read at a time.
+const CHUNK_SIZE: usize = 64 << 10;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarcConfig {
+    pub dir: String,
+    /// Compress each record as its own gzip member.
+    pub gzip: bool,
+    pub max_size: u64,
+}
+
+impl Default for WarcConfig {
+    fn default() -> Self {
+        Self {
+            dir: DEFAULT_WARC_DIR.to_owned(),
+            gzip: false,
+            max_size: DEFAULT_MAX_WARC_SIZE,
+        }
+    }
+}
+
+/// A WARC record, before it is serialized.
+#[derive(Debug)]
+pub struct WarcRecord {
+    pub id: String,
+    /// `WARC-Type`.
+    pub kind: &'static str,
+    pub date: String,
+    pub target_uri: Option<String>,
+    pub concurrent_to: Option<String>,
+    pub filename: Option<String>,
+    /// SHA-1 of the HTTP body, for `WARC-Payload-Digest`.
+    pub payload_digest: Option<Digest>,
+    pub content_type: &'static str,
+    pub block: Vec<u8>,
+    /// File whose content follows `block`, and its size,
+    /// for bodies streamed to disk rather than kept in memory.
+    pub payload_file: Option<(PathBuf, u64)>,
+}
+
+impl WarcRecord {
+    pub fn new(
+        kind: &'static str,
+        date: String,
+        content_type: &'static str,
+        block: Vec<u8>,
+    ) -> Self {
+        Self {
+            id: record_id(),
+            kind,
+            date,
+            target_uri: None,
+            concurrent_to: None,
+            filename: None,
+            payload_digest: None,
+            content_type,
+            block,
+            payload_file: None,
+        }
+    }
+
+    /// The header, up to the empty line before the block.
+    pub fn header(&self) -> String {
+        let mut header = format!(
+            "WARC/1.1\r\nWARC-Type: {}\r\nWARC-Record-ID: {}\r\nWARC-Date: {}\r\n",
+            self.kind, self.id, self.date
+        );
+        if let Some(target_uri) = &self.target_uri {
+            header += &format!("WARC-Target-URI: {target_uri}\r\n");
+        }
+        if let Some(concurrent_to) = &self.concurrent_to {
+            header += &format!("WARC-Concurrent-To: {concurrent_to}\r\n");
+        }
+        if let Some(filename) = &self.filename {
+            header += &format!("WARC-Filename: {filename}\r\n");
+        }
+        if let Some(payload_digest) = &self.payload_digest {
+            header += &format!(
+                "WARC-Payload-Digest: sha1:{}\r\n",
+                BASE32.encode(payload_digest)
+            );
+        }
+        let payload_size = self.payload_file.as_ref().map_or(0, |(_, size)| *size);
+        header += &format!(
+            "Content-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            self.content_type,
+            self.block.len() as u64 + payload_size
+        );
+        header
+    }
+
+    /// The whole record, without the content of `payload_file`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header().into_bytes();
+        bytes.extend_from_slice(&self.block);
+        bytes.extend_from_slice(b"\r\n\r\n");
+        bytes
+    }
+}
+
+/// A random version 4 UUID as `<urn:uuid:…>`.
+fn record_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex = HEXLOWER.encode(&bytes);
+    format!(
+        "<urn:uuid:{}-{}-{}-{}-{}>",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The request, response and metadata records of `exchange`.
+///
+/// The payload digest of the response is that of `exchange.body`,
+/// to be replaced if the body was streamed to disk.
+/// The request is reconstructed, since the client does not expose it.
+/// `Transfer-Encoding` is left out of the response
+/// because the body is stored decoded.
+pub fn exchange_records(
+    exchange: &Exchange,
+    user_agent: &str,
+    date: String,
+    fields: &[(&str, String)],
+) -> [WarcRecord; 3] {
+    let url = &exchange.url;
+    let version = format!("{:?}", exchange.version);
+
+    let mut response = format!(
+        "{version} {} {}\r\n",
+        exchange.status.as_str(),
+        exchange.status.canonical_reason().unwrap_or_default()
+    )
+    .into_bytes();
+    for (name, value) in &exchange.headers {
+        if name != TRANSFER_ENCODING {
+            response.extend_from_slice(name.as_str().as_bytes());
+            response.extend_from_slice(b": ");
+            response.extend_from_slice(value.as_bytes());
+            response.extend_from_slice(b"\r\n");
+        }
+    }
+    response.extend_from_slice(b"\r\n");
+    response.extend_from_slice(&exchange.body);
+    let mut response = WarcRecord::new(
+        "response",
+        date.clone(),
+        "application/http;msgtype=response",
+        response,
+    );
+    response.target_uri = Some(url.to_string());
+    response.payload_digest = Some(sha1(&exchange.body));
+
+    let mut target = url.path().to_owned();
+    if let Some(query) = url.query() {
+        target += "?";
+        target += query;
+    }
+    let mut host = url.host_str().unwrap_or_default().to_owned();
+    if let Some(port) = url.port() {
+        host += &format!(":{port}");
+    }
+    let request = format!(
+        "GET {target} {version}\r\nhost: {host}\r\nuser-agent: {user_agent}\r\naccept: */*\r\n\r\n"
+    );
+    let mut request = WarcRecord::new(
+        "request",
+        date.clone(),
+        "application/http;msgtype=request",
+        request.into_bytes(),
+    );
+    request.target_uri = Some(url.to_string());
+    request.concurrent_to = Some(response.id.clone());
+
+    let block: String = fields
+        .iter()
+        .map(|(name, value)| format!("{name}: {value}\r\n"))
+        .collect();
+    let mut metadata = WarcRecord::new("metadata", date, "application/warc-fields", block.into());
+    metadata.target_uri = Some(url.to_string());
+    metadata.concurrent_to = Some(response.id.clone());
+
+    [request, response, metadata]
+}
+
+/// Appends records to WARC files, starting a new file
+/// once the current one reaches `max_size`.
+#[derive(Debug)]
+pub struct WarcWriter {
+    cfg: WarcConfig,
+    /// Timestamp and random suffix shared by the names of the files of this run,
+    /// so that runs started in the same second do not share files.
+    prefix: String,
+    index: usize,
+    /// Bytes written to the current file, or `None` if it is not started.
+    size: Option<u64>,
+}
+
+impl WarcWriter {
+    pub fn new(cfg: WarcConfig) -> Self {
+        let timestamp = rfc3339(SystemTime::now()).replace(['-', ':', 'T', 'Z'], "");
+        let prefix = format!("{timestamp}-{:08x}", rand::random::<u32>());
+        Self {
+            cfg,
+            prefix,
+            index: 0,
+            size: None,
+        }
+    }
+
+    fn filename(&self) -> String {
+        let extension = if self.cfg.gzip { "warc.gz" } else { "warc" };
+        format!("{}-{:05}.{extension}", self.prefix, self.index)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        PathBuf::from(&self.cfg.dir).join(self.filename())
+    }
+
+    pub async fn write(&mut self, records: &[WarcRecord]) -> Result<()> {
+        let mut warcinfo = None;
+        match self.size {
+            Some(size) if size < self.cfg.max_size => {}
+            started => {
+                if started.is_some() {
+                    self.index += 1;
+                }
+                self.size = Some(0);
+                warcinfo = Some(self.warcinfo());
+            }
+        }
+        let mut file = append_file(self.path()).await?;
+        let mut written = 0;
+        for record in warcinfo.iter().chain(records) {
+            written += self.write_record(&mut file, record).await?;
+        }
+        file.flush().await?;
+        self.size = self.size.map(|size| size + written);
+        Ok(())
+    }
+
+    /// Write `record` to `file`, streaming its payload file if any,
+    /// and return the number of bytes written.
+    async fn write_record(&self, file: &mut File, record: &WarcRecord) -> Result<u64> {
+        let mut writer = RecordWriter::new(self.cfg.gzip);
+        writer.write(file, record.header().as_bytes()).await?;
+        writer.write(file, &record.block).await?;
+        if let Some((path, _)) = &record.payload_file {
+            let mut payload = File::open(path).await?;
+            let mut buffer = vec![0; CHUNK_SIZE];
+            loop {
+                let read = payload.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                writer.write(file, &buffer[..read]).await?;
+            }
+        }
+        writer.write(file, b"\r\n\r\n").await?;
+        writer.finish(file).await
+    }
+
+    fn warcinfo(&self) -> WarcRecord {
+        let block = format!(
+            "software: {}/{}\r\nformat: WARC File Format 1.1\r\n",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        let mut warcinfo = WarcRecord::new(
+            "warcinfo",
+            rfc3339(SystemTime::now()),
+            "application/warc-fields",
+            block.into_bytes(),
+        );
+        warcinfo.filename = Some(self.filename());
+        warcinfo
+    }
+}
+
+/// Writes one record to a file, as its own gzip member if compressed.
+struct RecordWriter {
+    encoder: Option<GzEncoder<Vec<u8>>>,
+    written: u64,
+}
+
+impl RecordWriter {
+    fn new(gzip: bool) -> Self {
+        Self {
+            encoder: gzip.then(|| GzEncoder::new(Vec::new(), Compression::default())),
+            written: 0,
+        }
+    }
+
+    async fn write(&mut self, file: &mut File, bytes: &[u8]) -> Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => {
+                encoder.write_all(bytes)?;
+                let compressed = take(encoder.get_mut());
+                file.write_all(&compressed).await?;
+                self.written += compressed.len() as u64;
+            }
+            None => {
+                file.write_all(bytes).await?;
+                self.written += bytes.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// End the gzip member, if any, and return the number of bytes written.
+    async fn finish(self, file: &mut File) -> Result<u64> {
+        let mut written = self.written;
+        if let Some(encoder) = self.encoder {
+            let rest = encoder.finish()?;
+            file.write_all(&rest).await?;
+            written += rest.len() as u64;
+        }
+        Ok(written)
+    }
+}